@@ -0,0 +1,465 @@
+use bernard::Bernard;
+use reqwest::StatusCode;
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore};
+
+/// A Service Account with its own Bernard instance.
+/// All accounts share the same database, see [`Accounts::lane`].
+pub(crate) struct Account {
+    pub(crate) path: PathBuf,
+    pub(crate) name: String,
+    bernard: Bernard,
+}
+
+impl Account {
+    pub(crate) fn new(path: PathBuf, bernard: Bernard) -> Self {
+        Self {
+            name: account_name(&path),
+            path,
+            bernard,
+        }
+    }
+}
+
+/// The name of a Service Account in the logs, which is the name of its key file.
+pub(crate) fn account_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Spreads the drives over the Service Accounts to spread the Drive API quota.
+pub(crate) struct Accounts {
+    accounts: Vec<Account>,
+    assignments: Mutex<Assignments>,
+    lanes: Lanes,
+}
+
+impl Accounts {
+    pub(crate) fn new(accounts: Vec<Account>) -> Self {
+        let assignments = Assignments::new(accounts.len());
+        let lanes = Lanes::new(accounts.len());
+
+        Self {
+            accounts,
+            assignments: Mutex::new(assignments),
+            lanes,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub(crate) fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.accounts.iter().map(|account| account.path.as_path())
+    }
+
+    /// Assign the drives to the accounts, see [`Assignments::assign`].
+    pub(crate) fn assign<'a, I>(&self, drives: I)
+    where
+        I: IntoIterator<Item = (&'a str, Option<usize>)>,
+    {
+        self.assignments.lock().unwrap().assign(drives)
+    }
+
+    /// The index of the account the drive is assigned to, together with the account.
+    pub(crate) fn get(&self, drive_id: &str) -> (usize, &Account, &Bernard) {
+        let index = self.assignments.lock().unwrap().get(drive_id);
+        let account = &self.accounts[index];
+        (index, account, &account.bernard)
+    }
+
+    /// The account to try after the given account, without moving the drive, see [`Accounts::reassign`].
+    ///
+    /// Drives which are explicitly assigned to an account never use another account.
+    pub(crate) fn next(&self, drive_id: &str, from: usize) -> Option<(usize, &Account, &Bernard)> {
+        let index = self.assignments.lock().unwrap().next(drive_id, from)?;
+        let account = &self.accounts[index];
+        Some((index, account, &account.bernard))
+    }
+
+    /// Move the drive to another account, once a sync with that account succeeded.
+    pub(crate) fn reassign(&self, drive_id: &str, from: usize, to: usize) {
+        self.assignments
+            .lock()
+            .unwrap()
+            .reassign(drive_id, from, to)
+    }
+
+    /// Wait until the database may be used by the account with the given index.
+    ///
+    /// Every Bernard has a connection pool of its own to the same SQLite database,
+    /// so only syncs of a single account run at the same time to avoid "database is locked" errors.
+    /// Syncs with the same account share its pool and run concurrently.
+    pub(crate) async fn lane(&self, index: usize) -> Lane<'_> {
+        self.lanes.enter(index).await
+    }
+
+    pub(crate) async fn close(self) {
+        for account in self.accounts {
+            account.bernard.close().await;
+        }
+    }
+}
+
+/// Lets the syncs of one account at a time use the database.
+///
+/// The database is handed over first come, first served, as the locks of Tokio are fair:
+/// once a sync of another account waits, new syncs of the active account wait behind it,
+/// so a busy account cannot keep the others waiting forever.
+#[derive(Debug)]
+struct Lanes {
+    /// Syncs wait here in order of arrival, until they may use the database.
+    queue: AsyncMutex<()>,
+    /// Held by the account using the database.
+    database: Arc<Semaphore>,
+    accounts: Vec<Mutex<Active>>,
+}
+
+/// The syncs of an account using the database.
+#[derive(Debug, Default)]
+struct Active {
+    syncs: usize,
+    database: Option<OwnedSemaphorePermit>,
+}
+
+/// The right to use the database, until dropped.
+#[must_use]
+pub(crate) struct Lane<'a> {
+    lanes: &'a Lanes,
+    index: usize,
+}
+
+impl Lanes {
+    fn new(accounts: usize) -> Self {
+        Self {
+            queue: AsyncMutex::new(()),
+            database: Arc::new(Semaphore::new(1)),
+            accounts: (0..accounts).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    async fn enter(&self, index: usize) -> Lane<'_> {
+        let _queue = self.queue.lock().await;
+
+        let joined = {
+            let mut active = self.accounts[index].lock().unwrap();
+            let joined = active.syncs > 0;
+            if joined {
+                active.syncs += 1;
+            }
+
+            joined
+        };
+
+        // Holds up the queue, so other syncs of this account wait behind any other account.
+        if !joined {
+            let database = self.database.clone().acquire_owned().await;
+            let database = database.expect("the database semaphore is never closed");
+
+            *self.accounts[index].lock().unwrap() = Active {
+                syncs: 1,
+                database: Some(database),
+            };
+        }
+
+        Lane { lanes: self, index }
+    }
+}
+
+impl Drop for Lane<'_> {
+    fn drop(&mut self) {
+        let mut active = self.lanes.accounts[self.index].lock().unwrap();
+
+        active.syncs -= 1;
+        if active.syncs == 0 {
+            active.database = None;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Assignment {
+    index: usize,
+    explicit: bool,
+}
+
+/// Which account each drive is synchronised with.
+#[derive(Debug)]
+struct Assignments {
+    accounts: usize,
+    drives: HashMap<String, Assignment>,
+}
+
+impl Assignments {
+    fn new(accounts: usize) -> Self {
+        Self {
+            accounts,
+            drives: HashMap::new(),
+        }
+    }
+
+    /// Assign every drive to either the given account or, round robin, to any of the accounts.
+    ///
+    /// Drives which were assigned before keep their account, including after a rotation.
+    fn assign<'a, I>(&mut self, drives: I)
+    where
+        I: IntoIterator<Item = (&'a str, Option<usize>)>,
+    {
+        let previous = std::mem::take(&mut self.drives);
+        let mut next = 0;
+
+        for (drive_id, explicit) in drives {
+            let assignment = match (explicit, previous.get(drive_id)) {
+                (Some(index), _) => Assignment {
+                    index,
+                    explicit: true,
+                },
+                (None, Some(assignment)) if !assignment.explicit => *assignment,
+                (None, _) => {
+                    next += 1;
+                    Assignment {
+                        index: (next - 1) % self.accounts,
+                        explicit: false,
+                    }
+                }
+            };
+
+            self.drives.insert(drive_id.to_owned(), assignment);
+        }
+    }
+
+    fn get(&self, drive_id: &str) -> usize {
+        self.drives
+            .get(drive_id)
+            .map_or(0, |assignment| assignment.index)
+    }
+
+    fn next(&self, drive_id: &str, from: usize) -> Option<usize> {
+        let assignment = self.drives.get(drive_id)?;
+
+        match assignment.explicit || self.accounts < 2 {
+            true => None,
+            false => Some((from + 1) % self.accounts),
+        }
+    }
+
+    fn reassign(&mut self, drive_id: &str, from: usize, to: usize) {
+        // Another sync of this drive might have moved it already.
+        if let Some(assignment) = self.drives.get_mut(drive_id) {
+            if !assignment.explicit && assignment.index == from {
+                assignment.index = to;
+            }
+        }
+    }
+}
+
+/// Whether Google rate limited the sync with `429 Too Many Requests`, so another Service Account might help.
+///
+/// A `403 Forbidden` is not retried, as Bernard does not keep the reason of the response
+/// and it usually means missing permissions.
+pub(crate) fn is_rate_limited(err: &bernard::Error) -> bool {
+    rate_limited(err)
+}
+
+fn rate_limited(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) {
+                return true;
+            }
+        }
+
+        source = err.source();
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rate_limited, Assignments, Lanes};
+    use futures::future;
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+    async fn response_error(status: u16) -> reqwest::Error {
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(status))
+            .mount(&server)
+            .await;
+
+        reqwest::get(server.uri())
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn rate_limits() {
+        assert!(rate_limited(&response_error(429).await));
+
+        // A `403 Forbidden` is usually a missing permission rather than a rate limit.
+        assert!(!rate_limited(&response_error(403).await));
+        assert!(!rate_limited(&response_error(404).await));
+        assert!(!rate_limited(&response_error(500).await));
+    }
+
+    #[tokio::test]
+    async fn lanes_keep_accounts_apart() {
+        let lanes = Lanes::new(2);
+
+        let first = lanes.enter(0).await;
+        // The same account may use the database concurrently.
+        let second = lanes.enter(0).await;
+
+        let other = lanes.enter(1);
+        tokio::pin!(other);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut other)
+            .await
+            .is_err());
+
+        drop(first);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut other)
+            .await
+            .is_err());
+
+        drop(second);
+        let _other = tokio::time::timeout(Duration::from_millis(10), &mut other)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lanes_are_handed_over_in_order() {
+        let lanes = Lanes::new(2);
+        let first = lanes.enter(0).await;
+
+        // A sync which stops waiting gives up its place.
+        {
+            let other = lanes.enter(1);
+            tokio::pin!(other);
+            assert!(tokio::time::timeout(Duration::from_millis(10), &mut other)
+                .await
+                .is_err());
+        }
+        let second = lanes.enter(0).await;
+
+        let other = lanes.enter(1);
+        tokio::pin!(other);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut other)
+            .await
+            .is_err());
+
+        // The active account does not pass the account which is waiting.
+        let third = lanes.enter(0);
+        tokio::pin!(third);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut third)
+            .await
+            .is_err());
+
+        drop(first);
+        drop(second);
+        let other = tokio::time::timeout(Duration::from_millis(10), &mut other)
+            .await
+            .unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut third)
+            .await
+            .is_err());
+
+        drop(other);
+        let _third = tokio::time::timeout(Duration::from_millis(10), &mut third)
+            .await
+            .unwrap();
+    }
+
+    /// A sync which is cancelled while using the database hands it over.
+    #[tokio::test]
+    async fn lanes_are_released_when_cancelled() {
+        let lanes = Arc::new(Lanes::new(2));
+
+        let sync = tokio::spawn({
+            let lanes = lanes.clone();
+            async move {
+                let _lane = lanes.enter(0).await;
+                future::pending::<()>().await;
+            }
+        });
+        tokio::task::yield_now().await;
+
+        let other = lanes.enter(1);
+        tokio::pin!(other);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut other)
+            .await
+            .is_err());
+
+        sync.abort();
+        let _other = tokio::time::timeout(Duration::from_millis(10), &mut other)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn round_robin_and_explicit() {
+        let mut assignments = Assignments::new(2);
+        assignments.assign(vec![("a", None), ("b", None), ("c", Some(0)), ("d", None)]);
+
+        assert_eq!(assignments.get("a"), 0);
+        assert_eq!(assignments.get("b"), 1);
+        assert_eq!(assignments.get("c"), 0);
+        assert_eq!(assignments.get("d"), 0);
+    }
+
+    #[test]
+    fn rotation() {
+        let mut assignments = Assignments::new(3);
+        assignments.assign(vec![("a", None), ("b", Some(1))]);
+
+        assert_eq!(assignments.next("a", 0), Some(1));
+        assert_eq!(assignments.next("a", 2), Some(0));
+        // Trying another account does not move the drive.
+        assert_eq!(assignments.get("a"), 0);
+
+        assignments.reassign("a", 0, 1);
+        assert_eq!(assignments.get("a"), 1);
+        // A concurrent sync which started with the old account does not move the drive again.
+        assignments.reassign("a", 0, 2);
+        assert_eq!(assignments.get("a"), 1);
+
+        assert_eq!(assignments.next("b", 1), None);
+        assignments.reassign("b", 1, 2);
+        assert_eq!(assignments.get("b"), 1);
+    }
+
+    #[test]
+    fn assignments_survive_reassignment() {
+        let mut assignments = Assignments::new(2);
+        assignments.assign(vec![("a", None)]);
+        assignments.reassign("a", 0, 1);
+
+        assignments.assign(vec![("a", None), ("b", None)]);
+        assert_eq!(assignments.get("a"), 1);
+        assert_eq!(assignments.get("b"), 0);
+    }
+
+    #[test]
+    fn single_account_never_rotates() {
+        let mut assignments = Assignments::new(1);
+        assignments.assign(vec![("a", None)]);
+
+        assert_eq!(assignments.next("a", 0), None);
+    }
+}
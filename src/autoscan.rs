@@ -1,19 +1,34 @@
+use crate::{
+    collapse::Collapse,
+    config::{redact_url, Paths, RetryConfig},
+    filter::Filter,
+    metrics,
+    retry::RetryPolicy,
+    rewrite::{rewrite_path, Rewrite},
+};
 use async_trait::async_trait;
 use bernard::{ChangedPath, Path};
 use eyre::eyre;
 use reqwest::{Client, ClientBuilder, IntoUrl, Request, Response, Url};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path as StdPath, PathBuf},
+};
 use thiserror::Error;
-use tower::{buffer::Buffer, util::BoxService, BoxError, Service as _, ServiceBuilder, ServiceExt};
+use tower::{
+    buffer::Buffer, retry::Retry, service_fn, util::BoxService, BoxError, Service as _, ServiceExt,
+};
 use tracing::debug;
 
-type Service = Buffer<BoxService<Request, Response, reqwest::Error>, Request>;
+pub(crate) type Service = Buffer<BoxService<Request, Response, reqwest::Error>, Request>;
 
 #[derive(Debug, Error)]
 pub enum AutoscanError {
     #[error("network error")]
     Network(#[from] eyre::Report),
+    #[error("invalid client configuration")]
+    Client(#[source] reqwest::Error),
 }
 
 impl From<BoxError> for AutoscanError {
@@ -29,19 +44,20 @@ impl From<reqwest::Error> for AutoscanError {
 }
 
 #[async_trait]
-trait RequestExt {
-    async fn svc_send<T: AsRef<Service> + Send>(
-        self,
-        service: T,
-    ) -> Result<Response, AutoscanError>;
+pub(crate) trait RequestExt {
+    async fn svc_send<T, E>(self, service: T) -> Result<Response, E>
+    where
+        T: AsRef<Service> + Send,
+        E: From<BoxError> + From<reqwest::Error>;
 }
 
 #[async_trait]
 impl RequestExt for reqwest::RequestBuilder {
-    async fn svc_send<T: AsRef<Service> + Send>(
-        self,
-        service: T,
-    ) -> Result<Response, AutoscanError> {
+    async fn svc_send<T, E>(self, service: T) -> Result<Response, E>
+    where
+        T: AsRef<Service> + Send,
+        E: From<BoxError> + From<reqwest::Error>,
+    {
         let mut service = service.as_ref().clone();
 
         let request = self.build()?;
@@ -54,6 +70,8 @@ impl RequestExt for reqwest::RequestBuilder {
 pub struct Autoscan {
     auth: Option<Credentials>,
     client: Client,
+    /// The name of the target, used in metrics as the URL might contain credentials.
+    name: String,
     service: Service,
     url: Url,
 }
@@ -65,19 +83,18 @@ impl AsRef<Service> for Autoscan {
 }
 
 impl Autoscan {
-    pub(crate) fn new(auth: Option<Credentials>, client: Client, url: Url) -> Self {
-        let service = {
-            let client = client.clone();
-            let service =
-                ServiceBuilder::new().service_fn(move |request: Request| client.execute(request));
-
-            Buffer::new(BoxService::new(service), 1024)
-        };
-
+    pub(crate) fn new(
+        name: String,
+        auth: Option<Credentials>,
+        client: Client,
+        url: Url,
+        retry: RetryPolicy,
+    ) -> Self {
         Self {
             auth,
+            service: retry_service(&client, retry),
             client,
-            service,
+            name,
             url,
         }
     }
@@ -87,68 +104,312 @@ impl Autoscan {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Send requests with the client, retrying them according to the policy.
+pub(crate) fn retry_service(client: &Client, retry: RetryPolicy) -> Service {
+    let client = client.clone();
+    let send = service_fn(move |request: Request| client.execute(request));
+    // Every request gets its own attempts, counting from the start of the first one.
+    let service = service_fn(move |request: Request| {
+        Retry::new(retry.start(), send.clone()).oneshot(request)
+    });
+
+    Buffer::new(BoxService::new(service), 1024)
+}
+
+#[derive(Debug)]
 pub struct Credentials {
-    username: String,
-    password: String,
+    pub(crate) username: String,
+    pub(crate) password: String,
 }
 
+/// Like reqwest's `ClientBuilder`, any invalid input is reported by [`AutoscanBuilder::build`].
 pub(crate) struct AutoscanBuilder {
     auth: Option<Credentials>,
     client: ClientBuilder,
-    url: Url,
+    error: Option<reqwest::Error>,
+    name: Option<String>,
+    retry: RetryPolicy,
+    url: reqwest::Result<Url>,
 }
 
 impl AutoscanBuilder {
     pub(crate) fn new<U: IntoUrl>(url: U, auth: Option<Credentials>) -> Self {
-        let url = url.into_url().expect("Invalid url");
-
         AutoscanBuilder {
             auth,
             client: ClientBuilder::new(),
-            url,
+            error: None,
+            name: None,
+            retry: RetryPolicy::from(&RetryConfig::default()),
+            url: url.into_url(),
         }
     }
 
+    /// Name the instance in metrics, defaults to its URL without credentials.
+    pub(crate) fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub(crate) fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
     pub(crate) fn proxy<U: IntoUrl>(mut self, url: U) -> Self {
-        let proxy = reqwest::Proxy::all(url).unwrap();
+        match reqwest::Proxy::all(url) {
+            Ok(proxy) => self.client = self.client.proxy(proxy),
+            Err(err) => self.error = self.error.or(Some(err)),
+        }
 
-        self.client = self.client.proxy(proxy);
         self
     }
 
-    pub(crate) fn build(self) -> Autoscan {
-        let client = self.client.build().unwrap();
-        Autoscan::new(self.auth, client, self.url)
+    pub(crate) fn build(self) -> Result<Autoscan, AutoscanError> {
+        if let Some(err) = self.error {
+            return Err(AutoscanError::Client(err));
+        }
+
+        let url = self.url.map_err(AutoscanError::Client)?;
+        let client = self.client.build().map_err(AutoscanError::Client)?;
+        let name = self
+            .name
+            .unwrap_or_else(|| redact_url(url.as_str()).into_owned());
+
+        Ok(Autoscan::new(name, self.auth, client, url, self.retry))
     }
 }
 
-#[derive(Debug, Default, Serialize)]
-#[cfg_attr(test, derive(Deserialize, PartialEq))]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
 pub(crate) struct Payload {
-    created: HashSet<PathBuf>,
-    deleted: HashSet<PathBuf>,
+    /// The created folders and the parent folders of created files.
+    pub(crate) created: HashSet<PathBuf>,
+    /// The deleted folders and the parent folders of deleted files.
+    pub(crate) deleted: HashSet<PathBuf>,
+    /// The created files and folders themselves.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) created_paths: HashSet<ChangedEntry>,
+    /// The deleted files and folders themselves.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) deleted_paths: HashSet<ChangedEntry>,
+    /// Files and folders which were both deleted and created, i.e. moved or renamed.
+    ///
+    /// Their old and new paths are part of the deleted and created paths as well.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) moved: HashSet<MovedEntry>,
+}
+
+/// A changed file or folder, for targets which scan individual files.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub(crate) struct ChangedEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) kind: Kind,
+}
+
+/// A file or folder which was moved or renamed within a drive.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub(crate) struct MovedEntry {
+    pub(crate) from: PathBuf,
+    pub(crate) to: PathBuf,
+    pub(crate) kind: Kind,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    File,
+    Folder,
 }
 
 impl Payload {
     pub(crate) fn is_empty(&self) -> bool {
-        self.created.len() == 0 && self.deleted.len() == 0
+        self.len() == 0
+    }
+
+    fn map_paths<F: Fn(PathBuf) -> PathBuf>(&mut self, f: F) {
+        let entry = |entry: ChangedEntry| ChangedEntry {
+            path: f(entry.path),
+            kind: entry.kind,
+        };
+        let moved = |moved: MovedEntry| MovedEntry {
+            from: f(moved.from),
+            to: f(moved.to),
+            kind: moved.kind,
+        };
+
+        self.created = self.created.drain().map(&f).collect();
+        self.deleted = self.deleted.drain().map(&f).collect();
+        self.created_paths = self.created_paths.drain().map(entry).collect();
+        self.deleted_paths = self.deleted_paths.drain().map(entry).collect();
+        self.moved = self.moved.drain().map(moved).collect();
+    }
+
+    /// Reduce the created and deleted folders below the root of the drive,
+    /// leaving the changed files and folders themselves as is.
+    pub(crate) fn collapse(&mut self, collapse: &Collapse, root: &StdPath) {
+        collapse.apply(&mut self.created, root);
+        collapse.apply(&mut self.deleted, root);
+    }
+
+    /// The number of entries sent to a target: the created and deleted folders,
+    /// the changed files and folders, and the moves.
+    ///
+    /// Only counts what a target receives once the payload is [selected](Payload::select) for it,
+    /// as selecting clears every field the target does not receive.
+    pub(crate) fn len(&self) -> usize {
+        self.created.len()
+            + self.deleted.len()
+            + self.created_paths.len()
+            + self.deleted_paths.len()
+            + self.moved.len()
+    }
+
+    /// Split the payload into payloads of at most the given number of paths.
+    ///
+    /// The paths are sorted first, so the same payload is always split the same way.
+    pub(crate) fn chunks(&self, max_paths: usize) -> Vec<Payload> {
+        fn sorted<T: Ord>(set: &HashSet<T>) -> Vec<&T> {
+            let mut items: Vec<_> = set.iter().collect();
+            items.sort();
+            items
+        }
+
+        fn next(chunks: &mut Vec<Payload>, max_paths: usize) -> &mut Payload {
+            if !matches!(chunks.last(), Some(chunk) if chunk.len() < max_paths) {
+                chunks.push(Payload::default());
+            }
+
+            chunks.last_mut().unwrap()
+        }
+
+        if self.len() <= max_paths {
+            return vec![self.clone()];
+        }
+
+        let mut chunks = Vec::new();
+        for path in sorted(&self.created) {
+            next(&mut chunks, max_paths).created.insert(path.clone());
+        }
+        for path in sorted(&self.deleted) {
+            next(&mut chunks, max_paths).deleted.insert(path.clone());
+        }
+        for entry in sorted(&self.created_paths) {
+            next(&mut chunks, max_paths)
+                .created_paths
+                .insert(entry.clone());
+        }
+        for entry in sorted(&self.deleted_paths) {
+            next(&mut chunks, max_paths)
+                .deleted_paths
+                .insert(entry.clone());
+        }
+        for moved in sorted(&self.moved) {
+            next(&mut chunks, max_paths).moved.insert(moved.clone());
+        }
+
+        chunks
+    }
+
+    /// The part of the payload a target is interested in.
+    pub(crate) fn select(&self, paths: Paths) -> Payload {
+        let mut payload = self.clone();
+
+        match paths {
+            // Moves are made of the paths of the files and folders themselves.
+            Paths::Folders => {
+                payload.created_paths.clear();
+                payload.deleted_paths.clear();
+                payload.moved.clear();
+            }
+            Paths::Files => {
+                payload.created.clear();
+                payload.deleted.clear();
+            }
+            Paths::Both => (),
+        }
+
+        payload
+    }
+
+    /// Prepend the given prefix to every created and deleted path.
+    pub(crate) fn prefix(&mut self, prefix: &StdPath) {
+        self.map_paths(|path| prefix.join(path.strip_prefix("/").unwrap_or(&path)));
+    }
+
+    /// Apply the rewrite rules in order to every created and deleted path.
+    pub(crate) fn rewrite<'a, I>(&mut self, rules: I)
+    where
+        I: IntoIterator<Item = &'a Rewrite> + Clone,
+    {
+        self.map_paths(|path| rewrite_path(rules.clone(), path));
+    }
+}
+
+impl ChangedEntry {
+    fn file(path: &StdPath) -> Self {
+        Self {
+            path: path.to_owned(),
+            kind: Kind::File,
+        }
+    }
+
+    fn folder(path: &StdPath) -> Self {
+        Self {
+            path: path.to_owned(),
+            kind: Kind::Folder,
+        }
     }
 }
 
-pub(crate) fn create_payload(changed_paths: Vec<ChangedPath>) -> Payload {
+/// Create the payload of the changed paths, skipping paths rejected by any of the filters.
+///
+/// Both the folders to scan and the changed files and folders themselves are kept,
+/// see [`Payload::select`].
+/// A deleted and created path of the same file or folder is also reported as a move.
+pub(crate) fn create_payload(changed_paths: Vec<ChangedPath>, filters: &[&Filter]) -> Payload {
     let mut payload = Payload::default();
 
+    // The deleted and created paths by the ID of their file or folder.
+    let mut created_ids = HashMap::new();
+    let mut deleted_ids = HashMap::new();
+
     for path in changed_paths {
+        // Filter before popping the file name, so files can be filtered on their extension.
+        let (inner, kind) = match &path {
+            ChangedPath::Created(path) | ChangedPath::Deleted(path) => match path {
+                Path::File(inner) => (inner, Kind::File),
+                Path::Folder(inner) => (inner, Kind::Folder),
+            },
+        };
+
+        if !filters.iter().all(|filter| filter.is_match(&inner.path)) {
+            continue;
+        }
+
+        match &path {
+            ChangedPath::Created(_) => {
+                created_ids.insert(inner.id.clone(), (inner.path.clone(), kind));
+            }
+            ChangedPath::Deleted(deleted) if !deleted.trashed() => {
+                deleted_ids.insert(inner.id.clone(), inner.path.clone());
+            }
+            ChangedPath::Deleted(_) => (),
+        }
+
         match path {
             ChangedPath::Created(path) => match path {
                 Path::File(mut file) => {
-                    // We're only interested in folders.
+                    payload.created_paths.insert(ChangedEntry::file(&file.path));
+
+                    // Autoscan is only interested in folders.
                     // Thus we pop the file and retrieve the parent instead.
                     file.path.pop();
                     payload.created.insert(file.path);
                 }
                 Path::Folder(folder) => {
+                    payload
+                        .created_paths
+                        .insert(ChangedEntry::folder(&folder.path));
                     payload.created.insert(folder.path);
                 }
             },
@@ -161,12 +422,17 @@ pub(crate) fn create_payload(changed_paths: Vec<ChangedPath>) -> Payload {
 
                 match path {
                     Path::File(mut file) => {
-                        // We're only interested in folders.
+                        payload.deleted_paths.insert(ChangedEntry::file(&file.path));
+
+                        // Autoscan is only interested in folders.
                         // Thus we pop the file and retrieve the parent instead.
                         file.path.pop();
                         payload.deleted.insert(file.path);
                     }
                     Path::Folder(folder) => {
+                        payload
+                            .deleted_paths
+                            .insert(ChangedEntry::folder(&folder.path));
                         payload.deleted.insert(folder.path);
                     }
                 }
@@ -174,6 +440,17 @@ pub(crate) fn create_payload(changed_paths: Vec<ChangedPath>) -> Payload {
         }
     }
 
+    // A file or folder which is deleted and created at the same path, e.g. restored from the trash,
+    // has not moved and is only part of the created and deleted paths.
+    payload.moved = deleted_ids
+        .into_iter()
+        .filter_map(|(id, from)| {
+            let (to, kind) = created_ids.remove(&id)?;
+            Some(MovedEntry { from, to, kind })
+        })
+        .filter(|moved| moved.from != moved.to)
+        .collect();
+
     payload
 }
 
@@ -184,7 +461,7 @@ impl Autoscan {
 
         self.client
             .get(url)
-            .svc_send(&self)
+            .svc_send::<_, AutoscanError>(self)
             .await?
             .error_for_status()?;
 
@@ -194,18 +471,30 @@ impl Autoscan {
     #[tracing::instrument(skip(self, payload))]
     pub(crate) async fn send_payload(
         &self,
+        trigger: &str,
         drive_id: &str,
         payload: &Payload,
     ) -> Result<(), AutoscanError> {
         let mut url = self.url.clone();
-        url.set_path(&format!("/triggers/a-train/{}", drive_id));
+        url.set_path(&format!("/triggers/{}/{}", trigger, drive_id));
 
         let mut request = self.client.post(url).json(&payload);
         if let Some(auth) = &self.auth {
             request = request.basic_auth(&auth.username, Some(&auth.password));
         }
 
-        request.svc_send(&self).await?.error_for_status()?;
+        let timer = std::time::Instant::now();
+        let response: Result<_, AutoscanError> = request.svc_send(self).await;
+
+        let status = match &response {
+            Ok(response) => response.status().as_str().to_owned(),
+            Err(_) => "error".to_owned(),
+        };
+        metrics::TARGET_REQUESTS
+            .with_label_values(&[&self.name, &status])
+            .observe(timer.elapsed().as_secs_f64());
+
+        response?.error_for_status()?;
         debug!("changes received by autoscan");
 
         Ok(())
@@ -215,10 +504,12 @@ impl Autoscan {
 #[cfg(test)]
 mod tests {
     use super::{create_payload, Autoscan, Payload};
+    use crate::{config::Paths, filter::Filter, retry::RetryPolicy};
     use bernard::{ChangedPath, InnerPath, Path};
     use pretty_assertions::assert_eq;
     use reqwest::{Client, Url};
     use serde_json::{from_value, json};
+    use std::time::Duration;
     use wiremock::matchers::{body_json, method, path};
     use wiremock::{Mock, ResponseTemplate};
 
@@ -241,9 +532,29 @@ mod tests {
         }
     }
 
+    /// Every path is a different file, so none of them are moves.
+    fn distinct_inner(path: &str) -> InnerPath {
+        InnerPath {
+            id: path.to_string(),
+            ..new_inner(path, false)
+        }
+    }
+
     impl Autoscan {
         fn new_test(url: &str) -> Self {
-            Autoscan::new(None, Client::new(), Url::parse(url).unwrap())
+            let retry = RetryPolicy::with_initial_interval(
+                3,
+                Duration::from_secs(10),
+                Duration::from_millis(1),
+            );
+
+            Autoscan::new(
+                "autoscan".to_owned(),
+                None,
+                Client::new(),
+                Url::parse(url).unwrap(),
+                retry,
+            )
         }
     }
 
@@ -252,10 +563,14 @@ mod tests {
         let server = wiremock::MockServer::start().await;
         let autoscan = Autoscan::new_test(&server.uri());
 
-        let payload: Payload = create_payload(vec![
-            new_path(true, true, new_inner("/this/is/a/full/path", false)),
-            new_path(false, true, new_inner("/just/like/me", false)),
-        ]);
+        let payload: Payload = create_payload(
+            vec![
+                new_path(true, true, new_inner("/this/is/a/full/path", false)),
+                new_path(false, true, new_inner("/just/like/me", false)),
+            ],
+            &[],
+        )
+        .select(Paths::Folders);
 
         let expected_body = json!({
             "created": [
@@ -274,7 +589,7 @@ mod tests {
             .mount(&server)
             .await;
 
-        let result = autoscan.send_payload("test123", &payload).await;
+        let result = autoscan.send_payload("a-train", "test123", &payload).await;
 
         // First drop the server to evaluate the request.
         drop(server);
@@ -283,13 +598,150 @@ mod tests {
         result.unwrap();
     }
 
+    /// The first attempt counts towards the maximum elapsed time of the retries.
+    #[tokio::test]
+    async fn slow_first_attempt_is_not_retried() {
+        let server = wiremock::MockServer::start().await;
+        let retry = RetryPolicy::with_initial_interval(
+            5,
+            Duration::from_millis(100),
+            Duration::from_millis(1),
+        );
+        let autoscan = Autoscan::new(
+            "slow".to_owned(),
+            None,
+            Client::new(),
+            Url::parse(&server.uri()).unwrap(),
+            retry,
+        );
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500).set_delay(Duration::from_millis(200)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = autoscan
+            .send_payload("a-train", "test123", &Payload::default())
+            .await;
+
+        drop(server);
+        assert!(result.is_err());
+    }
+
+    /// Requests are measured by the name of the target rather than its URL.
+    #[tokio::test]
+    async fn requests_are_measured_by_name() {
+        let server = wiremock::MockServer::start().await;
+        let autoscan = Autoscan::builder(server.uri(), None)
+            .name("measured")
+            .build()
+            .unwrap();
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        autoscan
+            .send_payload("a-train", "test123", &Payload::default())
+            .await
+            .unwrap();
+
+        let requests = crate::metrics::TARGET_REQUESTS.with_label_values(&["measured", "200"]);
+        assert_eq!(requests.get_sample_count(), 1);
+    }
+
+    /// Server errors are retried until the request succeeds.
+    #[tokio::test]
+    async fn autoscan_retries_server_errors() {
+        let server = wiremock::MockServer::start().await;
+        let autoscan = Autoscan::new_test(&server.uri());
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = autoscan
+            .send_payload("a-train", "test123", &Payload::default())
+            .await;
+
+        drop(server);
+        result.unwrap();
+    }
+
+    /// Retrying stops after the maximum number of attempts.
+    #[tokio::test]
+    async fn autoscan_retries_are_limited() {
+        let server = wiremock::MockServer::start().await;
+        let autoscan = Autoscan::new_test(&server.uri());
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let result = autoscan
+            .send_payload("a-train", "test123", &Payload::default())
+            .await;
+
+        drop(server);
+        assert!(result.is_err());
+    }
+
+    /// Client errors are surfaced immediately.
+    #[tokio::test]
+    async fn autoscan_does_not_retry_client_errors() {
+        let server = wiremock::MockServer::start().await;
+        let autoscan = Autoscan::new_test(&server.uri());
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = autoscan
+            .send_payload("a-train", "test123", &Payload::default())
+            .await;
+
+        drop(server);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn builder_reports_invalid_input() {
+        assert!(Autoscan::builder("http://localhost:3030", None)
+            .build()
+            .is_ok());
+        assert!(Autoscan::builder("localhost:3030", None).build().is_err());
+        assert!(Autoscan::builder("http://localhost:3030", None)
+            .proxy("not a proxy")
+            .build()
+            .is_err());
+    }
+
     /// Check whether folder paths keep as is.
     #[test]
     fn payload_folders_are_full_paths() {
-        let payload: Payload = create_payload(vec![
-            new_path(true, true, new_inner("/this/is/a/full/path", false)),
-            new_path(false, true, new_inner("/just/like/me", false)),
-        ]);
+        let payload: Payload = create_payload(
+            vec![
+                new_path(true, true, new_inner("/this/is/a/full/path", false)),
+                new_path(false, true, new_inner("/just/like/me", false)),
+            ],
+            &[],
+        )
+        .select(Paths::Folders);
 
         let expected_body = json!({
             "created": [
@@ -309,10 +761,14 @@ mod tests {
     /// Check whether file paths are transformed into the path of the parent.
     #[test]
     fn payload_files_are_parents() {
-        let payload: Payload = create_payload(vec![
-            new_path(true, false, new_inner("/keep me/but not me", false)),
-            new_path(false, false, new_inner("/where/is/perry", false)),
-        ]);
+        let payload: Payload = create_payload(
+            vec![
+                new_path(true, false, new_inner("/keep me/but not me", false)),
+                new_path(false, false, new_inner("/where/is/perry", false)),
+            ],
+            &[],
+        )
+        .select(Paths::Folders);
 
         let expected_body = json!({
             "created": [
@@ -329,14 +785,277 @@ mod tests {
         )
     }
 
+    /// Check whether the changed folders and files keep their own paths.
+    #[test]
+    fn payload_keeps_changed_paths() {
+        let payload: Payload = create_payload(
+            vec![
+                new_path(true, true, distinct_inner("/this/is/a/full/path")),
+                new_path(false, true, distinct_inner("/just/like/me")),
+                new_path(true, false, distinct_inner("/keep me/but not me")),
+                new_path(false, false, distinct_inner("/where/is/perry")),
+            ],
+            &[],
+        );
+
+        let expected_body = json!({
+            "created": [
+                "/this/is/a/full/path",
+                "/keep me",
+            ],
+            "deleted": [
+                "/just/like/me",
+                "/where/is",
+            ],
+            "created_paths": [
+                { "path": "/this/is/a/full/path", "kind": "folder" },
+                { "path": "/keep me/but not me", "kind": "file" },
+            ],
+            "deleted_paths": [
+                { "path": "/just/like/me", "kind": "folder" },
+                { "path": "/where/is/perry", "kind": "file" },
+            ],
+        });
+
+        assert_eq!(
+            payload,
+            from_value(expected_body).expect("could not deserialize")
+        )
+    }
+
+    /// Check whether the prefix is prepended to both created and deleted paths.
+    #[test]
+    fn payload_prefix() {
+        let mut payload: Payload = create_payload(
+            vec![
+                new_path(true, true, distinct_inner("/Movies/Foo")),
+                new_path(false, false, distinct_inner("/TV/Bar/episode.mkv")),
+            ],
+            &[],
+        );
+
+        payload.prefix("/mnt/drive".as_ref());
+
+        let expected_body = json!({
+            "created": [
+                "/mnt/drive/Movies/Foo",
+            ],
+            "deleted": [
+                "/mnt/drive/TV/Bar"
+            ],
+            "created_paths": [
+                { "path": "/mnt/drive/Movies/Foo", "kind": "folder" },
+            ],
+            "deleted_paths": [
+                { "path": "/mnt/drive/TV/Bar/episode.mkv", "kind": "file" },
+            ],
+        });
+
+        assert_eq!(
+            payload,
+            from_value(expected_body).expect("could not deserialize")
+        )
+    }
+
+    /// Check whether files are filtered on their own path, not the path of the parent.
+    #[test]
+    fn payload_is_filtered() {
+        let filter: Filter = toml::from_str(r#"exclude = ["**/*.nfo", "/.trash/**"]"#).unwrap();
+
+        let payload: Payload = create_payload(
+            vec![
+                new_path(true, false, distinct_inner("/Movies/Foo/foo.mkv")),
+                new_path(true, false, distinct_inner("/Movies/Bar/bar.nfo")),
+                new_path(false, true, distinct_inner("/.trash/Baz")),
+            ],
+            &[&filter],
+        );
+
+        let expected_body = json!({
+            "created": [
+                "/Movies/Foo",
+            ],
+            "deleted": [],
+            "created_paths": [
+                { "path": "/Movies/Foo/foo.mkv", "kind": "file" },
+            ],
+        });
+
+        assert_eq!(
+            payload,
+            from_value(expected_body).expect("could not deserialize")
+        )
+    }
+
+    /// Check whether a target only receives the paths it asked for.
+    #[test]
+    fn payload_selects_paths() {
+        let payload: Payload = create_payload(
+            vec![
+                new_path(true, false, distinct_inner("/Movies/Foo/foo.mkv")),
+                new_path(false, true, distinct_inner("/TV/Bar")),
+            ],
+            &[],
+        );
+
+        assert_eq!(
+            serde_json::to_value(payload.select(Paths::Folders)).unwrap(),
+            json!({
+                "created": ["/Movies/Foo"],
+                "deleted": ["/TV/Bar"],
+            })
+        );
+
+        assert_eq!(
+            serde_json::to_value(payload.select(Paths::Files)).unwrap(),
+            json!({
+                "created": [],
+                "deleted": [],
+                "created_paths": [{ "path": "/Movies/Foo/foo.mkv", "kind": "file" }],
+                "deleted_paths": [{ "path": "/TV/Bar", "kind": "folder" }],
+            })
+        );
+
+        assert_eq!(payload.select(Paths::Both), payload);
+
+        let renamed = |path: &str| InnerPath {
+            id: "renamed".to_string(),
+            ..new_inner(path, false)
+        };
+        let payload: Payload = create_payload(
+            vec![
+                new_path(false, false, renamed("/TV/Bar/old.mkv")),
+                new_path(true, false, renamed("/TV/Bar/new.mkv")),
+            ],
+            &[],
+        );
+
+        assert_eq!(payload.moved.len(), 1);
+        assert!(payload.select(Paths::Folders).moved.is_empty());
+        assert_eq!(payload.select(Paths::Files).moved, payload.moved);
+    }
+
+    /// Check whether a deleted and created path of the same file is reported as a move.
+    #[test]
+    fn payload_moves() {
+        let renamed = |path: &str| InnerPath {
+            id: "renamed".to_string(),
+            ..new_inner(path, false)
+        };
+
+        let payload: Payload = create_payload(
+            vec![
+                new_path(false, true, renamed("/TV/Show (2020)")),
+                new_path(true, true, renamed("/TV/Show (2021)")),
+                new_path(true, false, distinct_inner("/TV/Other/episode.mkv")),
+                new_path(false, false, distinct_inner("/TV/Gone/episode.mkv")),
+            ],
+            &[],
+        );
+
+        let expected_body = json!({
+            "created": ["/TV/Show (2021)", "/TV/Other"],
+            "deleted": ["/TV/Show (2020)", "/TV/Gone"],
+            "created_paths": [
+                { "path": "/TV/Show (2021)", "kind": "folder" },
+                { "path": "/TV/Other/episode.mkv", "kind": "file" },
+            ],
+            "deleted_paths": [
+                { "path": "/TV/Show (2020)", "kind": "folder" },
+                { "path": "/TV/Gone/episode.mkv", "kind": "file" },
+            ],
+            "moved": [
+                { "from": "/TV/Show (2020)", "to": "/TV/Show (2021)", "kind": "folder" },
+            ],
+        });
+
+        assert_eq!(
+            payload,
+            from_value(expected_body).expect("could not deserialize")
+        )
+    }
+
+    /// A file which is deleted and created at the same path has not moved.
+    #[test]
+    fn payload_restore_is_not_a_move() {
+        let restored = |path: &str| InnerPath {
+            id: "restored".to_string(),
+            ..new_inner(path, false)
+        };
+
+        let payload: Payload = create_payload(
+            vec![
+                new_path(false, false, restored("/TV/Show/episode.mkv")),
+                new_path(true, false, restored("/TV/Show/episode.mkv")),
+            ],
+            &[],
+        );
+
+        let expected_body = json!({
+            "created": ["/TV/Show"],
+            "deleted": ["/TV/Show"],
+            "created_paths": [{ "path": "/TV/Show/episode.mkv", "kind": "file" }],
+            "deleted_paths": [{ "path": "/TV/Show/episode.mkv", "kind": "file" }],
+        });
+
+        assert_eq!(
+            payload,
+            from_value(expected_body).expect("could not deserialize")
+        )
+    }
+
+    /// Check whether a large payload is split the same way every time.
+    #[test]
+    fn payload_chunks() {
+        let payload: Payload = from_value(json!({
+            "created": ["/c", "/a", "/b"],
+            "deleted": ["/e", "/d"],
+            "created_paths": [{ "path": "/a/file.mkv", "kind": "file" }],
+        }))
+        .unwrap();
+
+        // Compared as payloads, as the order of the paths in JSON is arbitrary.
+        let expected: Vec<Payload> = vec![
+            json!({ "created": ["/a", "/b"], "deleted": [] }),
+            json!({ "created": ["/c"], "deleted": ["/d"] }),
+            json!({
+                "created": [],
+                "deleted": ["/e"],
+                "created_paths": [{ "path": "/a/file.mkv", "kind": "file" }],
+            }),
+        ]
+        .into_iter()
+        .map(|chunk| from_value(chunk).unwrap())
+        .collect();
+        assert_eq!(payload.chunks(2), expected);
+
+        assert_eq!(payload.chunks(6), vec![payload.clone()]);
+        assert_eq!(Payload::default().chunks(1), vec![Payload::default()]);
+
+        // Only the folders sent to a target are counted, not the files and moves it does not receive.
+        let moved: Payload = from_value(json!({
+            "created": ["/b"],
+            "deleted": ["/a"],
+            "created_paths": [{ "path": "/b", "kind": "folder" }],
+            "deleted_paths": [{ "path": "/a", "kind": "folder" }],
+            "moved": [{ "from": "/a", "to": "/b", "kind": "folder" }],
+        }))
+        .unwrap();
+        assert_eq!(moved.len(), 5);
+        assert_eq!(moved.select(Paths::Folders).chunks(2).len(), 1);
+    }
+
     /// Check whether file paths are transformed into the path of the parent.
     #[test]
     fn trashed_deleted_is_skipped() {
-        let payload: Payload = create_payload(vec![new_path(
-            false,
-            false,
-            new_inner("/trashed/and/now/deleted", true),
-        )]);
+        let payload: Payload = create_payload(
+            vec![new_path(
+                false,
+                false,
+                new_inner("/trashed/and/now/deleted", true),
+            )],
+            &[],
+        );
 
         let expected_body = json!({
             "created": [],
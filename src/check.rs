@@ -0,0 +1,161 @@
+use crate::{
+    accounts::account_name,
+    config::{load_account, redact_url},
+    dry_run::DatabaseCopy,
+    google::{Google, SharedDrive},
+    status::error_chain,
+    target::TargetBuilder,
+    Config, Result,
+};
+use bernard::{Account, Bernard, SyncKind};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The outcome of a single check of [`check`].
+pub struct Check {
+    /// What was checked.
+    pub name: String,
+    /// Details on success, or the reason of the failure.
+    pub result: std::result::Result<String, String>,
+}
+
+impl Check {
+    fn new<E: std::error::Error>(name: String, result: std::result::Result<String, E>) -> Self {
+        Self {
+            name,
+            result: result.map_err(|err| error_chain(&err)),
+        }
+    }
+}
+
+/// Check whether every Service Account is valid,
+/// every Autoscan instance and webhook is available and every drive is accessible.
+///
+/// Drives are checked with every Service Account they might be synchronised with,
+/// by synchronising them with Bernard like A-Train does, on a temporary copy of the database.
+/// A drive which has not been synchronised before is therefore listed in full.
+/// Every check is performed, even when an earlier check failed.
+pub async fn check(config: Config, database: &Path, proxy: Option<&str>) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let mut accounts = Vec::new();
+    for path in &config.drive.account {
+        let result = load_account(path);
+        checks.push(Check::new(
+            format!("Service Account {}", account_name(path)),
+            result.as_ref().map(|_| format!("{:?}", path)),
+        ));
+
+        if let Ok(account) = result {
+            accounts.push((path.as_path(), account));
+        }
+    }
+
+    match DatabaseCopy::new(database) {
+        Ok(copy) => checks.extend(check_drives(&config, copy.path(), accounts, proxy).await),
+        Err(err) => checks.push(Check::new(format!("Database {:?}", database), Err(err))),
+    }
+
+    for autoscan in config.autoscan {
+        let name = format!("Autoscan {}", autoscan.name());
+        let url = redact_url(&autoscan.url).into_owned();
+        let mut target = TargetBuilder::new(autoscan);
+        if let Some(proxy) = proxy {
+            target = target.proxy(proxy);
+        }
+
+        let result = match target.build() {
+            Ok(target) => target.available().await.map(|_| url),
+            Err(err) => Err(err),
+        };
+        checks.push(Check::new(name, result));
+    }
+
+    for webhook in config.webhook {
+        let name = format!("Webhook {}", webhook.name());
+        let url = webhook.url.as_str().to_owned();
+        let mut target = TargetBuilder::webhook(webhook);
+        if let Some(proxy) = proxy {
+            target = target.proxy(proxy);
+        }
+
+        let result = match target.build() {
+            Ok(target) => target.available().await.map(|_| url),
+            Err(err) => Err(err),
+        };
+        checks.push(Check::new(name, result));
+    }
+
+    checks
+}
+
+/// Synchronise every drive with each of the Service Accounts it might be synchronised with.
+async fn check_drives(
+    config: &Config,
+    database: &Path,
+    accounts: Vec<(&Path, Account)>,
+    proxy: Option<&str>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let database = database.to_string_lossy();
+    let single = config.drive.account.len() == 1;
+
+    for (path, account) in accounts {
+        let mut bernard = Bernard::builder(&database, account);
+        if let Some(url) = proxy {
+            bernard = bernard.proxy(url);
+        }
+
+        let bernard = match bernard.build().await {
+            Ok(bernard) => bernard,
+            Err(err) => {
+                let name = format!("Google Drive {}", account_name(path));
+                checks.push(Check::new(name, Err(err)));
+                continue;
+            }
+        };
+
+        let drives = config.drive.enabled().filter(|drive| match &drive.account {
+            Some(account) => account == path,
+            None => true,
+        });
+
+        for drive in drives {
+            let result = match bernard.sync_drive(&drive.id).await {
+                Ok(SyncKind::Full) => Ok("listed in full".to_owned()),
+                Ok(SyncKind::Partial(_)) => Ok("changes fetched".to_owned()),
+                // The drive is accessible, Google just could not list all of its changes yet.
+                Err(err) if err.is_partial_change_list() => Ok("changes fetched".to_owned()),
+                Err(err) => Err(err),
+            };
+
+            let name = match single {
+                true => format!("Drive {}", drive.display_name()),
+                false => format!("Drive {} ({})", drive.display_name(), account_name(path)),
+            };
+            checks.push(Check::new(name, result));
+        }
+
+        bernard.close().await;
+    }
+
+    checks
+}
+
+/// All Shared Drives visible to any of the Service Accounts.
+pub async fn shared_drives(config: &Config, proxy: Option<&str>) -> Result<Vec<SharedDrive>> {
+    let mut seen = HashSet::new();
+    let mut drives = Vec::new();
+
+    for path in &config.drive.account {
+        let google = Google::connect(path, proxy).await?;
+
+        for drive in google.shared_drives().await? {
+            if seen.insert(drive.id.clone()) {
+                drives.push(drive);
+            }
+        }
+    }
+
+    Ok(drives)
+}
@@ -0,0 +1,286 @@
+use serde::{de, Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Reduces the folders sent to Autoscan to fewer folders covering the same changes.
+///
+/// A folder within another folder of the same set is dropped, as scanning the outer folder covers it.
+/// Optionally, many sibling folders are replaced by their parent folder,
+/// but never by the root of the drive or any folder above it.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Collapse {
+    #[serde(default)]
+    enabled: bool,
+    /// Replace this many, or more, sibling folders by their parent folder.
+    #[serde(default, deserialize_with = "deserialize_siblings")]
+    siblings: Option<usize>,
+}
+
+impl Collapse {
+    /// Collapse the folders of a drive, of which every path starts with the given root.
+    pub(crate) fn apply(&self, paths: &mut HashSet<PathBuf>, root: &Path) {
+        if !self.enabled {
+            return;
+        }
+
+        loop {
+            remove_covered(paths);
+
+            let parents = match self.siblings {
+                Some(siblings) => crowded_parents(paths, siblings, root),
+                None => Vec::new(),
+            };
+
+            // The parents might be siblings of other folders in turn.
+            if parents.is_empty() {
+                return;
+            }
+
+            paths.extend(parents);
+        }
+    }
+}
+
+/// Drop every path of which an ancestor is part of the set as well.
+fn remove_covered(paths: &mut HashSet<PathBuf>) {
+    let covered: Vec<_> = paths
+        .iter()
+        .filter(|path| path.ancestors().skip(1).any(|a| paths.contains(a)))
+        .cloned()
+        .collect();
+
+    for path in covered {
+        paths.remove(&path);
+    }
+}
+
+/// The parents of at least the given number of paths, below the root.
+fn crowded_parents(paths: &HashSet<PathBuf>, siblings: usize, root: &Path) -> Vec<PathBuf> {
+    let mut children = HashMap::new();
+    let parents = paths
+        .iter()
+        .filter_map(|path| path.parent())
+        .filter(|parent| *parent != root && parent.starts_with(root));
+
+    for parent in parents {
+        *children.entry(parent).or_insert(0) += 1;
+    }
+
+    children
+        .into_iter()
+        .filter(|(_, count)| *count >= siblings)
+        .map(|(parent, _)| parent.to_owned())
+        .collect()
+}
+
+fn deserialize_siblings<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<usize>::deserialize(deserializer)? {
+        Some(siblings) if siblings < 2 => Err(de::Error::custom(
+            "siblings must be at least 2 to keep any folder",
+        )),
+        siblings => Ok(siblings),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Collapse;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+
+    fn collapse(siblings: Option<usize>, paths: &[&str]) -> Vec<PathBuf> {
+        collapse_within("/", siblings, paths)
+    }
+
+    fn collapse_within(root: &str, siblings: Option<usize>, paths: &[&str]) -> Vec<PathBuf> {
+        let collapse = Collapse {
+            enabled: true,
+            siblings,
+        };
+
+        let mut paths = paths.iter().map(PathBuf::from).collect();
+        collapse.apply(&mut paths, root.as_ref());
+
+        let mut paths: Vec<_> = paths.into_iter().collect();
+        paths.sort();
+        paths
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn covered_paths_are_removed() {
+        assert_eq!(
+            collapse(
+                None,
+                &[
+                    "/TV/Show",
+                    "/TV/Show/Season 1",
+                    "/TV/Show/Season 2",
+                    "/TV/Shows",
+                    "/Movies/Foo",
+                ]
+            ),
+            paths(&["/Movies/Foo", "/TV/Show", "/TV/Shows"])
+        );
+    }
+
+    #[test]
+    fn siblings_roll_up() {
+        let episodes = &[
+            "/TV/Show/Season 1",
+            "/TV/Show/Season 2",
+            "/TV/Show/Season 3",
+            "/TV/Other/Season 1",
+            "/TV/Other/Season 2",
+        ];
+
+        assert_eq!(
+            collapse(Some(3), episodes),
+            paths(&["/TV/Other/Season 1", "/TV/Other/Season 2", "/TV/Show"])
+        );
+
+        // A rolled up parent counts as a sibling of its own siblings.
+        assert_eq!(collapse(Some(2), episodes), paths(&["/TV"]));
+    }
+
+    #[test]
+    fn siblings_stay_below_the_root() {
+        let libraries = &["/Movies", "/Music", "/TV"];
+        assert_eq!(collapse(Some(2), libraries), paths(libraries));
+
+        let libraries = &["/mnt/drive/Movies", "/mnt/drive/Music", "/mnt/drive/TV"];
+        assert_eq!(
+            collapse_within("/mnt/drive", Some(2), libraries),
+            paths(libraries)
+        );
+
+        // Rewritten paths outside of the root are never rolled up either.
+        let elsewhere = &["/mnt/other/Movies", "/mnt/other/TV"];
+        assert_eq!(
+            collapse_within("/mnt/drive", Some(2), elsewhere),
+            paths(elsewhere)
+        );
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let mut paths: HashSet<_> = paths(&["/TV/Show", "/TV/Show/Season 1"])
+            .into_iter()
+            .collect();
+        let expected = paths.clone();
+
+        Collapse::default().apply(&mut paths, "/".as_ref());
+        assert_eq!(paths, expected);
+
+        assert!(toml::from_str::<Collapse>("siblings = 1").is_err());
+    }
+
+    /// A deterministic source of pseudo random numbers, so failures can be reproduced.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % n
+        }
+
+        /// A set of paths below the root with few distinct names, so paths often share their ancestors.
+        fn paths(&mut self, root: &Path) -> HashSet<PathBuf> {
+            (0..self.below(30))
+                .map(|_| {
+                    let mut path = root.to_owned();
+                    for _ in 0..=self.below(4) {
+                        path.push(["a", "b", "c", "d"][self.below(4)]);
+                    }
+                    path
+                })
+                .collect()
+        }
+    }
+
+    fn is_covered(path: &Path, by: &HashSet<PathBuf>) -> bool {
+        path.ancestors().any(|ancestor| by.contains(ancestor))
+    }
+
+    #[test]
+    fn properties() {
+        let mut random = Random(42);
+
+        for _ in 0..500 {
+            let root = Path::new(["/", "/mnt", "/mnt/drive"][random.below(3)]);
+            let input = random.paths(root);
+            let siblings = match random.below(3) {
+                0 => None,
+                _ => Some(2 + random.below(3)),
+            };
+
+            let collapse = Collapse {
+                enabled: true,
+                siblings,
+            };
+
+            let mut output = input.clone();
+            collapse.apply(&mut output, root);
+
+            // Every changed folder is still scanned.
+            for path in &input {
+                assert!(is_covered(path, &output), "{:?} lost in {:?}", path, output);
+            }
+
+            // Never the root or a folder above it, unless it changed itself.
+            for path in &output {
+                assert!(
+                    input.contains(path) || (path != root && path.starts_with(root)),
+                    "{:?} is not below {:?}",
+                    path,
+                    root
+                );
+            }
+
+            // No folder is scanned twice.
+            for path in &output {
+                assert!(
+                    !path.ancestors().skip(1).any(|a| output.contains(a)),
+                    "{:?} is covered in {:?}",
+                    path,
+                    output
+                );
+            }
+
+            match siblings {
+                // Only the given folders are scanned.
+                None => assert!(output.is_subset(&input)),
+                // Every folder scanned is needed for at least one of the given folders.
+                Some(siblings) => {
+                    for path in &output {
+                        assert!(input.iter().any(|p| p.starts_with(path)));
+                    }
+
+                    // Except for the siblings directly within the root.
+                    let mut parents = std::collections::HashMap::new();
+                    let below_root = |parent: &&Path| *parent != root && parent.starts_with(root);
+                    for parent in output.iter().filter_map(|p| p.parent()).filter(below_root) {
+                        *parents.entry(parent).or_insert(0) += 1;
+                    }
+                    assert!(parents.values().all(|count| *count < siblings));
+                }
+            }
+
+            // Collapsing again changes nothing.
+            let mut again = output.clone();
+            collapse.apply(&mut again, root);
+            assert_eq!(again, output);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::autoscan::{ChangedEntry, Kind, MovedEntry, Payload};
+use crate::config::DebounceConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, Instant};
+
+/// Holds back the changes of folders which are still changing, e.g. during a large upload.
+///
+/// The changes of a folder are released once the folder has been quiet for a while,
+/// or once its first change has been held back for too long.
+/// Pending changes are kept in memory, and stored in the outbox as [`Held`] changes to survive a restart.
+#[derive(Debug, Default)]
+pub(crate) struct Debouncer {
+    drives: HashMap<String, Pending>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    payload: Payload,
+    /// When each folder changed for the first and the last time.
+    folders: HashMap<PathBuf, (Instant, Instant)>,
+}
+
+impl Debouncer {
+    /// Add the changes of a sync of the drive and take the changes which are due.
+    ///
+    /// Without a configuration, every pending change is due.
+    pub(crate) fn push(
+        &mut self,
+        drive_id: &str,
+        payload: Payload,
+        config: Option<&DebounceConfig>,
+        now: Instant,
+    ) -> Payload {
+        let pending = self.drives.entry(drive_id.to_owned()).or_default();
+
+        for folder in folders(&payload) {
+            pending
+                .folders
+                .entry(folder.to_owned())
+                .and_modify(|(_, last)| *last = now)
+                .or_insert((now, now));
+        }
+        merge(&mut pending.payload, payload);
+
+        pending.folders.retain(|_, (first, last)| match config {
+            Some(config) => {
+                now.duration_since(*last) < Duration::from_secs(config.quiet)
+                    && now.duration_since(*first) < Duration::from_secs(config.max_wait)
+            }
+            None => false,
+        });
+
+        let folders = &pending.folders;
+        let due = split_off(&mut pending.payload, |folder| !folders.contains_key(folder));
+
+        if pending.folders.is_empty() {
+            self.drives.remove(drive_id);
+        }
+
+        due
+    }
+
+    /// Whether any changes of the drive are held back.
+    pub(crate) fn is_holding(&self, drive_id: &str) -> bool {
+        self.drives.contains_key(drive_id)
+    }
+
+    /// Take the pending changes of every drive, e.g. before shutting down.
+    pub(crate) fn drain(&mut self) -> Vec<(String, Payload)> {
+        self.drain_unless(|_| false)
+    }
+
+    /// Take the pending changes of every drive which should not be kept, e.g. after removing drives.
+    pub(crate) fn drain_unless<F>(&mut self, keep: F) -> Vec<(String, Payload)>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let drained: Vec<_> = self
+            .drives
+            .keys()
+            .filter(|drive_id| !keep(drive_id))
+            .cloned()
+            .collect();
+
+        drained
+            .into_iter()
+            .filter_map(|drive_id| {
+                let pending = self.drives.remove(&drive_id)?;
+                Some((drive_id, pending.payload))
+            })
+            .collect()
+    }
+
+    /// The pending changes of the drive, with the times of its folders on the wall clock.
+    pub(crate) fn held(&self, drive_id: &str, now: Instant, wall: SystemTime) -> Option<Held> {
+        let pending = self.drives.get(drive_id)?;
+        let seconds = |at: Instant| seconds(wall).saturating_sub(now.duration_since(at).as_secs());
+
+        let mut folders: Vec<_> = pending
+            .folders
+            .iter()
+            .map(|(path, (first, last))| HeldFolder {
+                path: path.clone(),
+                first: seconds(*first),
+                last: seconds(*last),
+            })
+            .collect();
+        folders.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Some(Held {
+            payload: pending.payload.clone(),
+            folders,
+        })
+    }
+
+    /// Hold back the changes stored before a restart again.
+    pub(crate) fn restore(&mut self, drive_id: &str, held: Held, now: Instant, wall: SystemTime) {
+        // Times before the start of the monotonic clock are treated as now.
+        let instant = |at: u64| {
+            now.checked_sub(Duration::from_secs(seconds(wall).saturating_sub(at)))
+                .unwrap_or(now)
+        };
+
+        let pending = self.drives.entry(drive_id.to_owned()).or_default();
+        for folder in held.folders {
+            pending
+                .folders
+                .insert(folder.path, (instant(folder.first), instant(folder.last)));
+        }
+        merge(&mut pending.payload, held.payload);
+    }
+}
+
+/// The pending changes of a drive as stored in the outbox,
+/// with the first and last change of every folder in seconds since the Unix epoch.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub(crate) struct Held {
+    payload: Payload,
+    folders: Vec<HeldFolder>,
+}
+
+impl Held {
+    pub(crate) fn into_payload(self) -> Payload {
+        self.payload
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+struct HeldFolder {
+    path: PathBuf,
+    first: u64,
+    last: u64,
+}
+
+fn seconds(wall: SystemTime) -> u64 {
+    wall.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The folder of a changed file is its parent folder.
+fn entry_folder(path: &Path, kind: Kind) -> &Path {
+    match kind {
+        Kind::File => path.parent().unwrap_or(path),
+        Kind::Folder => path,
+    }
+}
+
+fn changed_folder(entry: &ChangedEntry) -> &Path {
+    entry_folder(&entry.path, entry.kind)
+}
+
+/// A move is held back together with the changes of its new location.
+fn moved_folder(moved: &MovedEntry) -> &Path {
+    entry_folder(&moved.to, moved.kind)
+}
+
+/// The folders the changes of the payload belong to.
+fn folders(payload: &Payload) -> impl Iterator<Item = &Path> {
+    let folders = payload.created.iter().chain(&payload.deleted);
+    let entries = payload.created_paths.iter().chain(&payload.deleted_paths);
+
+    folders
+        .map(PathBuf::as_path)
+        .chain(entries.map(changed_folder))
+        .chain(payload.moved.iter().map(moved_folder))
+}
+
+fn merge(into: &mut Payload, payload: Payload) {
+    into.created.extend(payload.created);
+    into.deleted.extend(payload.deleted);
+    into.created_paths.extend(payload.created_paths);
+    into.deleted_paths.extend(payload.deleted_paths);
+    into.moved.extend(payload.moved);
+}
+
+/// Remove and return the changes of the folders which are due.
+fn split_off<F>(payload: &mut Payload, due: F) -> Payload
+where
+    F: Fn(&Path) -> bool,
+{
+    Payload {
+        created: take(&mut payload.created, |path| due(path)),
+        deleted: take(&mut payload.deleted, |path| due(path)),
+        created_paths: take(&mut payload.created_paths, |e| due(changed_folder(e))),
+        deleted_paths: take(&mut payload.deleted_paths, |e| due(changed_folder(e))),
+        moved: take(&mut payload.moved, |moved| due(moved_folder(moved))),
+    }
+}
+
+fn take<T, F>(set: &mut HashSet<T>, due: F) -> HashSet<T>
+where
+    T: Eq + Hash,
+    F: Fn(&T) -> bool,
+{
+    let (taken, kept) = set.drain().partition(|item| due(item));
+    *set = kept;
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debouncer;
+    use crate::autoscan::Payload;
+    use crate::config::DebounceConfig;
+    use pretty_assertions::assert_eq;
+    use std::time::UNIX_EPOCH;
+    use tokio::time::{Duration, Instant};
+
+    const CONFIG: DebounceConfig = DebounceConfig {
+        quiet: 120,
+        max_wait: 300,
+    };
+
+    fn created(paths: &[&str]) -> Payload {
+        Payload {
+            created: paths.iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn quiet_folders_are_released() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+
+        let push = |debouncer: &mut Debouncer, paths: &[&str], at: u64| {
+            debouncer.push("drive", created(paths), Some(&CONFIG), secs(start, at))
+        };
+
+        assert!(push(&mut debouncer, &["/Movies/Foo", "/TV/Show"], 0).is_empty());
+        assert!(push(&mut debouncer, &["/TV/Show"], 60).is_empty());
+        assert_eq!(push(&mut debouncer, &[], 120), created(&["/Movies/Foo"]));
+        assert!(push(&mut debouncer, &[], 150).is_empty());
+        assert_eq!(push(&mut debouncer, &[], 180), created(&["/TV/Show"]));
+        assert!(debouncer.drives.is_empty());
+    }
+
+    #[test]
+    fn busy_folders_are_released_after_max_wait() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+
+        for at in (0..300).step_by(60) {
+            let due = debouncer.push(
+                "drive",
+                created(&["/TV/Show"]),
+                Some(&CONFIG),
+                secs(start, at),
+            );
+            assert!(due.is_empty());
+        }
+
+        let due = debouncer.push(
+            "drive",
+            created(&["/TV/Show"]),
+            Some(&CONFIG),
+            secs(start, 300),
+        );
+        assert_eq!(due, created(&["/TV/Show"]));
+    }
+
+    #[test]
+    fn released_without_config() {
+        let mut debouncer = Debouncer::default();
+        let now = Instant::now();
+
+        assert!(debouncer
+            .push("drive", created(&["/Movies/Foo"]), Some(&CONFIG), now)
+            .is_empty());
+        assert_eq!(
+            debouncer.push("drive", created(&["/TV/Show"]), None, now),
+            created(&["/Movies/Foo", "/TV/Show"])
+        );
+    }
+
+    #[test]
+    fn held_changes_survive_a_restart() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+        let wall = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let push = |debouncer: &mut Debouncer, paths: &[&str], at: Instant| {
+            debouncer.push("drive", created(paths), Some(&CONFIG), at)
+        };
+
+        assert!(push(&mut debouncer, &["/TV/Show"], start).is_empty());
+        assert!(push(&mut debouncer, &["/TV/Show"], secs(start, 60)).is_empty());
+        let held = debouncer.held("drive", secs(start, 60), wall).unwrap();
+        assert_eq!(debouncer.held("other", start, wall), None);
+
+        // Restarted 30 seconds later, on a monotonic clock starting anew.
+        let mut restarted = Debouncer::default();
+        let restart = secs(Instant::now(), 1000);
+        let wall = wall + Duration::from_secs(30);
+        restarted.restore("drive", held, restart, wall);
+
+        assert!(push(&mut restarted, &[], secs(restart, 89)).is_empty());
+        assert_eq!(
+            push(&mut restarted, &[], secs(restart, 90)),
+            created(&["/TV/Show"])
+        );
+        assert_eq!(restarted.held("drive", restart, wall), None);
+    }
+
+    #[test]
+    fn drain_removed_drives() {
+        let mut debouncer = Debouncer::default();
+        let now = Instant::now();
+
+        for drive_id in ["kept", "removed"] {
+            debouncer.push(drive_id, created(&["/TV/Show"]), Some(&CONFIG), now);
+        }
+
+        let drained = debouncer.drain_unless(|drive_id| drive_id == "kept");
+        assert_eq!(
+            drained,
+            vec![("removed".to_owned(), created(&["/TV/Show"]))]
+        );
+        assert!(debouncer.drives.contains_key("kept"));
+    }
+}
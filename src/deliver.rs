@@ -0,0 +1,260 @@
+use crate::{autoscan::Payload, config::Drive, Atrain, Result, Settings};
+use futures::prelude::*;
+use std::collections::HashSet;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
+
+const DEFAULT_TRIGGER: &str = "a-train";
+const REDELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+impl Atrain {
+    /// Send the payload to every target interested in the drive.
+    ///
+    /// The payload is stored in the outbox first and only removed once delivered,
+    /// so a payload which could not be delivered is retried by [`Atrain::run_outbox`].
+    /// Every target is attempted, even when an earlier target failed.
+    /// A failed delivery is logged and reported in the status of the target, but is not an error,
+    /// as the payload is safe in the outbox.
+    /// When the payload could not be stored, it is still sent directly,
+    /// and only fails when it could not be delivered either.
+    ///
+    /// A payload split into chunks is stored and delivered chunk by chunk.
+    /// Every chunk is attempted, and only the chunks which failed are left for redelivery,
+    /// so chunks are not necessarily delivered in order.
+    pub(crate) async fn send_payload(
+        &self,
+        settings: &Settings,
+        drive: &Drive,
+        payload: &Payload,
+    ) -> Result<()> {
+        let trigger = drive.trigger.as_deref().unwrap_or(DEFAULT_TRIGGER);
+
+        if let Some(dry_run) = &self.dry_run {
+            for target in settings.targets.iter().filter(|t| t.accepts(&drive.id)) {
+                for chunk in target.payloads(payload) {
+                    dry_run.write(&target.name, trigger, &drive.id, &chunk)?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        let results = future::join_all(
+            settings
+                .targets
+                .iter()
+                .filter(|target| target.accepts(&drive.id))
+                .map(|target| async move {
+                    let chunks = target.payloads(payload);
+
+                    // The changes are already committed by Bernard, so send them anyway.
+                    let mut stored = Ok(());
+                    let ids = match self
+                        .outbox
+                        .push_all(&target.name, trigger, &drive.id, &chunks)
+                        .await
+                    {
+                        Ok(ids) => ids.into_iter().map(Some).collect(),
+                        Err(err) => {
+                            error!(target = %target.name, error = %err, "Could not store changes in the outbox, sending them directly.");
+                            stored = Err(err);
+                            vec![None; chunks.len()]
+                        }
+                    };
+
+                    let total = chunks.len();
+                    let mut failure = None;
+                    let mut removal = Ok(());
+
+                    for (i, (id, chunk)) in ids.into_iter().zip(&chunks).enumerate() {
+                        match (target.send_payload(trigger, &drive.id, chunk).await, id) {
+                            (Ok(()), Some(id)) => {
+                                // Keep delivering the other chunks, the chunk is redelivered at worst.
+                                if let Err(err) = self.outbox.remove(id).await {
+                                    removal = removal.and(Err(err));
+                                }
+                            }
+                            (Ok(()), None) => (),
+                            (Err(err), Some(id)) => {
+                                self.outbox.release(id);
+                                error!(target = %target.name, chunk = i + 1, chunks = total, error = %err, "Could not send changes to the target, will retry later.");
+                                failure.get_or_insert(err);
+                            }
+                            (Err(err), None) => {
+                                error!(target = %target.name, chunk = i + 1, chunks = total, error = %err, "Could not send changes to the target, they are lost.");
+                                failure.get_or_insert(err);
+                            }
+                        }
+                    }
+
+                    self.status.target(&target.name, failure.as_ref().map(|err| err as _));
+
+                    // Only lost when neither stored nor delivered.
+                    if failure.is_some() {
+                        stored?;
+                    }
+                    Ok(removal?)
+                }),
+        )
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    /// Attempt to deliver every payload left in the outbox.
+    ///
+    /// Once delivery to a target fails, its remaining payloads are left for the next attempt.
+    /// Never redelivers during a dry run.
+    pub async fn redeliver(&self) -> Result<()> {
+        if self.dry_run.is_some() {
+            return Ok(());
+        }
+
+        let settings = self.settings();
+        let mut unavailable = HashSet::new();
+
+        for entry in self.outbox.pending().await? {
+            // Leave the remaining entries for the next start.
+            if self.is_shutting_down() {
+                self.outbox.release(entry.id);
+                continue;
+            }
+
+            let target = settings
+                .targets
+                .iter()
+                .find(|target| target.name == entry.target);
+
+            let target = match target {
+                Some(target) if !unavailable.contains(&target.name) => target,
+                Some(_) => {
+                    self.outbox.release(entry.id);
+                    continue;
+                }
+                None => {
+                    warn!(target = %entry.target, "Skipping changes for an unknown target.");
+                    self.outbox.release(entry.id);
+                    continue;
+                }
+            };
+
+            let result = target
+                .send_payload(&entry.trigger, &entry.drive_id, &entry.payload)
+                .await;
+            self.status
+                .target(&target.name, result.as_ref().err().map(|err| err as _));
+
+            match result {
+                Ok(()) => {
+                    if let Err(err) = self.outbox.remove(entry.id).await {
+                        error!(error = %err, "Could not remove delivered changes from the outbox.");
+                    }
+
+                    info!(target = %target.name, drive_id = %entry.drive_id, "Redelivered changes to the target.");
+                }
+                Err(err) => {
+                    self.outbox.release(entry.id);
+                    warn!(target = %target.name, error = %err, "The target is still unavailable.");
+                    unavailable.insert(target.name.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Periodically redeliver payloads left in the outbox, starting immediately.
+    ///
+    /// Completes once a shutdown has been requested, after finishing the payload being delivered.
+    pub async fn run_outbox(&self) {
+        while !self.is_shutting_down() {
+            if let Err(err) = self.redeliver().await {
+                error!(error = %err, "Could not read the outbox.");
+            }
+
+            tokio::select! {
+                _ = sleep(REDELIVERY_INTERVAL) => (),
+                _ = self.shutdown_requested() => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        accounts::Accounts, autoscan::Payload, config::Drive, config::Source, outbox::Outbox,
+        target::TargetBuilder, Atrain, Settings,
+    };
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn a_train(outbox: Outbox, server: &MockServer) -> (Atrain, Settings) {
+        let config = toml::from_str(&format!("url = \"{}\"", server.uri())).unwrap();
+        let settings = Settings {
+            targets: vec![TargetBuilder::new(config).build().unwrap()],
+            ..Settings::default()
+        };
+        let a_train = Atrain::new(
+            Accounts::new(Vec::new()),
+            None,
+            outbox,
+            None,
+            None,
+            Source::default(),
+        );
+
+        (a_train, settings)
+    }
+
+    /// Changes which cannot be stored are still delivered.
+    #[tokio::test]
+    async fn unavailable_outbox_sends_directly() {
+        let server = MockServer::start().await;
+        let outbox = Outbox::memory().await.unwrap();
+        outbox.close().await;
+        let (a_train, settings) = a_train(outbox, &server);
+
+        Mock::given(method("POST"))
+            .and(path("/triggers/a-train/test123"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = a_train
+            .send_payload(
+                &settings,
+                &Drive::from_id("test123".to_owned()),
+                &Payload::default(),
+            )
+            .await;
+
+        drop(server);
+        result.unwrap();
+    }
+
+    /// Changes which can neither be stored nor delivered are reported.
+    #[tokio::test]
+    async fn unavailable_outbox_and_target_fail() {
+        let server = MockServer::start().await;
+        let outbox = Outbox::memory().await.unwrap();
+        outbox.close().await;
+        let (a_train, settings) = a_train(outbox, &server);
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+
+        let result = a_train
+            .send_payload(
+                &settings,
+                &Drive::from_id("test123".to_owned()),
+                &Payload::default(),
+            )
+            .await;
+
+        assert!(result.is_err());
+    }
+}
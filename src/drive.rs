@@ -1,22 +1,143 @@
-use crate::{autoscan::create_payload, Atrain, Result};
+use crate::{
+    accounts::is_rate_limited,
+    autoscan::{create_payload, Payload},
+    config::{Drive, FailurePolicy},
+    metrics,
+    target::Target,
+    Atrain, Error, Result, Settings,
+};
 use bernard::SyncKind;
 use futures::prelude::*;
-use tracing::warn;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::time::SystemTime;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
 
 const CONCURRENCY: usize = 5;
 
+/// The number of paths sent to the targets during the sync of a drive.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub(crate) struct Summary {
+    /// The created and deleted folders.
+    pub(crate) created: usize,
+    pub(crate) deleted: usize,
+    /// The created and deleted files and folders themselves.
+    pub(crate) created_paths: usize,
+    pub(crate) deleted_paths: usize,
+}
+
+impl Summary {
+    pub(crate) fn paths(&self) -> usize {
+        self.created + self.deleted + self.created_paths + self.deleted_paths
+    }
+
+    /// The paths of the payload received by at least one of the targets.
+    fn received<'a, I>(payload: &Payload, targets: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Target>,
+    {
+        targets
+            .into_iter()
+            .map(|target| Self::from(&payload.select(target.paths)))
+            .fold(Self::default(), |a, b| Self {
+                created: a.created.max(b.created),
+                deleted: a.deleted.max(b.deleted),
+                created_paths: a.created_paths.max(b.created_paths),
+                deleted_paths: a.deleted_paths.max(b.deleted_paths),
+            })
+    }
+}
+
+impl From<&Payload> for Summary {
+    fn from(payload: &Payload) -> Self {
+        Self {
+            created: payload.created.len(),
+            deleted: payload.deleted.len(),
+            created_paths: payload.created_paths.len(),
+            deleted_paths: payload.deleted_paths.len(),
+        }
+    }
+}
+
 impl Atrain {
-    async fn sync_drive(&self, drive_id: &str) -> Result<()> {
-        match self.bernard.sync_drive(drive_id).await {
+    #[tracing::instrument(level = "debug", skip(self, settings, drive), fields(drive = %drive.display_name()))]
+    async fn sync_drive(&self, settings: &Settings, drive: &Drive) -> Result<Summary> {
+        let name = drive.display_name();
+        let timer = metrics::SYNC_DURATION
+            .with_label_values(&[name])
+            .start_timer();
+
+        let result = self.sync_changes(settings, drive).await;
+        timer.observe_duration();
+
+        // Every sync is counted once, as only its outcome is known.
+        let kind = match &result {
+            Ok((kind, _)) => *kind,
+            Err(_) => Some("failed"),
+        };
+        if let Some(kind) = kind {
+            metrics::SYNCS.with_label_values(&[name, kind]).inc();
+        }
+
+        result.map(|(_, summary)| summary)
+    }
+
+    /// Fetch and send the changes of the drive,
+    /// returning the kind of sync unless a Partial Change List was encountered.
+    async fn sync_changes(
+        &self,
+        settings: &Settings,
+        drive: &Drive,
+    ) -> Result<(Option<&'static str>, Summary)> {
+        let drive_id = drive.id.as_str();
+        let name = drive.display_name();
+        let mut summary = Summary::default();
+
+        let (assigned, mut account, mut bernard) = self.accounts.get(drive_id);
+        let mut index = assigned;
+        let mut rotations = 0;
+        let (result, lane) = loop {
+            let lane = self.accounts.lane(index).await;
+            let result = bernard.sync_drive(drive_id).await;
+
+            // Retry with the next Service Account, until every account has been tried once.
+            if let Err(err) = &result {
+                if is_rate_limited(err) && rotations + 1 < self.accounts.len() {
+                    if let Some((next, next_account, next_bernard)) =
+                        self.accounts.next(drive_id, index)
+                    {
+                        warn!(drive = %name, from = %account.name, to = %next_account.name, "Rate limited by Google, trying the next Service Account.");
+                        index = next;
+                        account = next_account;
+                        bernard = next_bernard;
+                        rotations += 1;
+                        continue;
+                    }
+                }
+            }
+
+            break (result, lane);
+        };
+
+        // Only once another account succeeded does the drive keep using it.
+        if result.is_ok() && index != assigned {
+            self.accounts.reassign(drive_id, assigned, index);
+            info!(drive = %name, account = %account.name, "Switched Service Account.");
+        }
+
+        let (kind, payload) = match result {
             // Do not send a payload to Autoscan on a full scan
-            Ok(SyncKind::Full) => (),
+            Ok(SyncKind::Full) => (Some("full"), Payload::default()),
             Ok(SyncKind::Partial(changes)) => {
                 let changed_paths = changes.paths().await?;
-                let payload = create_payload(changed_paths);
-
-                if !payload.is_empty() {
-                    self.autoscan.send_payload(drive_id, &payload).await?;
+                let mut payload = create_payload(changed_paths, &[&settings.filter, &drive.filter]);
+                payload.rewrite(drive.rewrite.iter().chain(&settings.rewrite));
+                if let Some(prefix) = &drive.prefix {
+                    payload.prefix(prefix);
                 }
+
+                (Some("partial"), payload)
             }
             Err(err) => {
                 // Can ignore a Partial Change List as it should recover eventually.
@@ -24,25 +145,225 @@ impl Atrain {
                     return Err(err.into());
                 }
 
-                warn!(%drive_id, "Encountered a Partial Change List.")
+                metrics::PARTIAL_CHANGE_LISTS
+                    .with_label_values(&[name])
+                    .inc();
+                warn!(drive = %name, "Encountered a Partial Change List.");
+                (None, Payload::default())
+            }
+        };
+
+        // The changes have been read from the database, so other accounts may use it again.
+        drop(lane);
+
+        // Changes held back by an earlier sync might be due, even without any new changes.
+        let now = Instant::now();
+        let (mut payload, held, holding) = {
+            let mut debouncer = self.debouncer.lock().unwrap();
+            let holding = debouncer.is_holding(drive_id);
+            // A drive removed by a reload during the sync is not held back anymore, see `Atrain::reload`.
+            let config = settings
+                .debounce
+                .as_ref()
+                .filter(|_| self.settings().drives.contains_key(drive_id));
+            let due = debouncer.push(drive_id, payload, config, now);
+            (
+                due,
+                debouncer.held(drive_id, now, SystemTime::now()),
+                holding,
+            )
+        };
+
+        payload.collapse(&settings.collapse, &drive.root(&settings.rewrite));
+
+        if !payload.is_empty() {
+            let targets = settings.targets.iter().filter(|t| t.accepts(drive_id));
+            summary = Summary::received(&payload, targets);
+            for (change, paths) in [
+                ("created", summary.created),
+                ("deleted", summary.deleted),
+                ("created_paths", summary.created_paths),
+                ("deleted_paths", summary.deleted_paths),
+            ] {
+                metrics::PAYLOAD_PATHS
+                    .with_label_values(&[name, change])
+                    .observe(paths as f64);
             }
+
+            self.send_payload(settings, drive, &payload).await?;
         }
 
-        Ok(())
+        // The held back changes are no longer known to Bernard, so they are stored to survive a crash.
+        // The stored changes include those which were due, so they are only replaced once those are in the outbox.
+        if holding || held.is_some() {
+            self.outbox.hold(drive_id, held.as_ref()).await?;
+        }
+
+        Ok((kind, summary))
     }
 
+    /// Synchronise every configured Shared Drive, regardless of its schedule.
+    ///
+    /// Fails when any of the drives failed to synchronise.
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn sync(&self) -> Result<()> {
+        let settings = self.settings();
+        let drives = self.scheduler.lock().unwrap().drives();
+        let results = self.sync_drives(&settings, &drives).await;
+
+        let failed: Vec<_> = results
+            .into_iter()
+            .filter_map(|(drive, result)| {
+                let err = result.err()?;
+                error!(drive = %drive.display_name(), error = ?err, "Could not synchronise drive.");
+                Some(drive.display_name().to_owned())
+            })
+            .collect();
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Drives(failed))
+        }
+    }
+
+    /// Synchronise the given drives, returning the result of each drive.
+    ///
+    /// A failing drive does not affect the sync of the other drives.
+    pub(crate) async fn sync_drives<'a>(
+        &self,
+        settings: &'a Settings,
+        drives: &[String],
+    ) -> Vec<(&'a Drive, Result<Summary>)> {
         // also fetch changes here and create+send response to Autoscan for each individual Drive.
         // https://stackoverflow.com/questions/51044467
-        stream::iter(&self.drives)
-            .map(|drive_id| self.sync_drive(drive_id))
+        stream::iter(drives)
+            .filter_map(|drive_id| future::ready(settings.drives.get(drive_id)))
+            .map(|drive| {
+                self.sync_drive(settings, drive)
+                    .map(move |result| (drive, result))
+            })
             .buffer_unordered(CONCURRENCY)
-            .try_collect()
+            .collect()
             .await
     }
 
+    /// Reschedule the drives according to their result and the failure policy.
+    ///
+    /// Only fails when the policy is to exit and a drive failed too many consecutive times.
+    pub(crate) fn reschedule(
+        &self,
+        settings: &Settings,
+        results: Vec<(&Drive, Result<Summary>)>,
+    ) -> Result<()> {
+        let policy = settings.failure.on_failure;
+        let backoff = policy == FailurePolicy::Backoff;
+
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let now = Instant::now();
+
+        let total = results.len();
+        let mut failed = Vec::new();
+        let mut exhausted = None;
+
+        for (drive, result) in results {
+            let err = match result {
+                Ok(summary) => {
+                    scheduler.completed(&drive.id, now);
+                    self.status.synced(&drive.id, &summary);
+                    self.status.scheduled(&drive.id, scheduler.next(&drive.id));
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            let failures = scheduler.failed(&drive.id, now, backoff);
+            self.status.failed(&drive.id, &err, failures);
+            self.status.scheduled(&drive.id, scheduler.next(&drive.id));
+            error!(drive = %drive.display_name(), failures, error = ?err, "Could not synchronise drive.");
+            failed.push(drive.display_name().to_owned());
+
+            if policy == FailurePolicy::Exit && failures >= settings.failure.max_failures {
+                exhausted = Some((drive.display_name().to_owned(), failures));
+            }
+        }
+
+        if !failed.is_empty() {
+            warn!(
+                ?failed,
+                "{} of {} drives failed to synchronise.",
+                failed.len(),
+                total
+            );
+        } else if total > 0 {
+            debug!("Synchronised {} drives.", total);
+        }
+
+        match exhausted {
+            Some((drive, failures)) => Err(Error::TooManyFailures(drive, failures)),
+            None => Ok(()),
+        }
+    }
+
+    /// Send the changes held back by the debounce, regardless of their folders being quiet.
+    async fn flush_debounced(&self) {
+        let pending = self.debouncer.lock().unwrap().drain();
+        self.send_held(pending).await
+    }
+
+    /// Send the held back changes of the drives and forget them once stored in the outbox.
+    ///
+    /// The changes of drives which are no longer configured are sent to the targets accepting their ID,
+    /// like the changes of a drive configured by its ID only.
+    /// Changes which could not be stored are kept in the database, to be held back again on the next start.
+    pub(crate) async fn send_held(&self, pending: Vec<(String, Payload)>) {
+        let settings = self.settings();
+
+        for (drive_id, mut payload) in pending {
+            let drive = match settings.drives.get(&drive_id) {
+                Some(drive) => Cow::Borrowed(drive),
+                None => Cow::Owned(Drive::from_id(drive_id.clone())),
+            };
+
+            payload.collapse(&settings.collapse, &drive.root(&settings.rewrite));
+            if let Err(err) = self.send_payload(&settings, &drive, &payload).await {
+                error!(drive = %drive.display_name(), error = ?err, "Could not send held back changes.");
+                continue;
+            }
+
+            if let Err(err) = self.outbox.hold(&drive_id, None).await {
+                error!(drive = %drive.display_name(), error = %err, "Could not forget the held back changes.");
+            }
+        }
+    }
+
+    /// Hold back the changes which were held back when A-Train stopped, or crashed, again.
+    ///
+    /// The changes of drives which are no longer configured are sent right away, see [`Atrain::send_held`].
+    pub(crate) async fn restore_held(&self) -> Result<()> {
+        let settings = self.settings();
+        let held = self.outbox.held().await?;
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut removed = Vec::new();
+
+        {
+            let mut debouncer = self.debouncer.lock().unwrap();
+            for (drive_id, held) in held {
+                match settings.drives.contains_key(&drive_id) {
+                    true => debouncer.restore(&drive_id, held, now, wall),
+                    false => removed.push((drive_id, held.into_payload())),
+                }
+            }
+        }
+
+        self.send_held(removed).await;
+        Ok(())
+    }
+
+    /// Send any held back changes and close the connections.
     pub async fn close(self) {
-        self.bernard.close().await
+        self.flush_debounced().await;
+        self.outbox.close().await;
+        self.accounts.close().await
     }
 }
@@ -0,0 +1,185 @@
+use crate::autoscan::Payload;
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// SQLite keeps uncommitted changes next to the database in files with these suffixes.
+const DATABASE_SUFFIXES: [&str; 3] = ["", "-wal", "-shm"];
+
+/// Writes payloads as JSON lines instead of sending them to Autoscan.
+pub(crate) struct DryRun {
+    output: Mutex<Box<dyn Write + Send>>,
+    /// The copy of the database, removed once the dry run is over.
+    database: Option<DatabaseCopy>,
+}
+
+/// A temporary copy of the database, removed when dropped.
+pub(crate) struct DatabaseCopy {
+    path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    target: &'a str,
+    trigger: &'a str,
+    drive_id: &'a str,
+    payload: &'a Payload,
+}
+
+impl DryRun {
+    /// Write to stdout, or append to the file at the given path.
+    pub(crate) fn new(path: Option<&Path>) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(Self {
+            output: Mutex::new(output),
+            database: None,
+        })
+    }
+
+    /// Copy the database to a temporary file, so the dry run does not advance the progress of any drive.
+    pub(crate) fn copy_database(&mut self, path: &Path) -> io::Result<PathBuf> {
+        let copy = DatabaseCopy::new(path)?;
+        let path = copy.path().to_owned();
+
+        self.database = Some(copy);
+        Ok(path)
+    }
+
+    pub(crate) fn write(
+        &self,
+        target: &str,
+        trigger: &str,
+        drive_id: &str,
+        payload: &Payload,
+    ) -> io::Result<()> {
+        let line = Line {
+            target,
+            trigger,
+            drive_id,
+            payload,
+        };
+
+        let mut output = self.output.lock().unwrap();
+        serde_json::to_writer(&mut *output, &line)?;
+        writeln!(output)?;
+        output.flush()
+    }
+}
+
+impl DatabaseCopy {
+    /// Without an existing database, the copy starts from scratch.
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let copy = Self {
+            path: std::env::temp_dir().join(format!(
+                "a-train-copy-{}-{}.db",
+                std::process::id(),
+                nanos
+            )),
+        };
+
+        for suffix in DATABASE_SUFFIXES {
+            match std::fs::copy(with_suffix(path, suffix), with_suffix(&copy.path, suffix)) {
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(copy)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DatabaseCopy {
+    fn drop(&mut self) {
+        for suffix in DATABASE_SUFFIXES {
+            let _ = std::fs::remove_file(with_suffix(&self.path, suffix));
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DryRun;
+    use crate::autoscan::Payload;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn payloads_are_appended_as_lines() {
+        let dir = crate::test_dir("dry-run-output");
+        let output = dir.join("changes.jsonl");
+
+        for drive_id in &["first", "second"] {
+            let dry_run = DryRun::new(Some(&output)).unwrap();
+            dry_run
+                .write("plex", "a-train", drive_id, &Payload::default())
+                .unwrap();
+        }
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                json!({ "target": "plex", "trigger": "a-train", "drive_id": "first", "payload": { "created": [], "deleted": [] } }),
+                json!({ "target": "plex", "trigger": "a-train", "drive_id": "second", "payload": { "created": [], "deleted": [] } }),
+            ]
+        );
+    }
+
+    #[test]
+    fn database_is_copied_and_removed() {
+        let dir = crate::test_dir("dry-run-database");
+        let database = dir.join("a-train.db");
+        std::fs::write(&database, "original").unwrap();
+        std::fs::write(dir.join("a-train.db-wal"), "journal").unwrap();
+
+        let mut dry_run = DryRun::new(Some(&dir.join("changes.jsonl"))).unwrap();
+        let copy = dry_run.copy_database(&database).unwrap();
+
+        // Changes to the copy leave the original untouched.
+        assert_ne!(copy, database);
+        std::fs::write(&copy, "changed").unwrap();
+        assert_eq!(std::fs::read_to_string(&database).unwrap(), "original");
+        assert_eq!(
+            std::fs::read_to_string(super::with_suffix(&copy, "-wal")).unwrap(),
+            "journal"
+        );
+
+        drop(dry_run);
+        assert!(!copy.exists());
+        assert!(!super::with_suffix(&copy, "-wal").exists());
+        assert!(database.exists());
+
+        // Without a database, a dry run starts from scratch.
+        let mut dry_run = DryRun::new(Some(&dir.join("changes.jsonl"))).unwrap();
+        let copy = dry_run.copy_database(&dir.join("missing.db")).unwrap();
+        assert!(!copy.exists());
+    }
+}
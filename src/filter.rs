@@ -0,0 +1,137 @@
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use std::path::Path;
+
+/// Decides which changed paths are sent to Autoscan.
+///
+/// Patterns are matched against the full path of the changed file or folder,
+/// before a file is replaced by its parent folder.
+/// This allows files to be filtered on their extension, e.g. `**/*.nfo`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Filter {
+    /// When not empty, only paths matching at least one pattern are kept.
+    #[serde(default)]
+    include: Vec<Pattern>,
+    /// Paths matching any of these patterns are dropped.
+    #[serde(default)]
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    pub(crate) fn is_match(&self, path: &Path) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|p| p.is_match(path));
+        included && !self.exclude.iter().any(|p| p.is_match(path))
+    }
+}
+
+/// Either a glob (a plain string) or a regular expression (`{ regex = "..." }`).
+#[derive(Clone, Debug)]
+enum Pattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn is_match(&self, path: &Path) -> bool {
+        match self {
+            Self::Glob(glob) => glob.is_match(path),
+            Self::Regex(regex) => matches!(path.to_str(), Some(path) if regex.is_match(path)),
+        }
+    }
+
+    fn glob(pattern: &str) -> Result<Self, globset::Error> {
+        let glob: Glob = GlobBuilder::new(pattern).literal_separator(true).build()?;
+        Ok(Self::Glob(glob.compile_matcher()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Glob(String),
+            Regex { regex: String },
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Glob(pattern) => Self::glob(&pattern).map_err(de::Error::custom),
+            Raw::Regex { regex } => Regex::new(&regex)
+                .map(Self::Regex)
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, Pattern};
+    use regex::Regex;
+    use std::path::Path;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        Filter {
+            include: include.iter().map(|p| Pattern::glob(p).unwrap()).collect(),
+            exclude: exclude.iter().map(|p| Pattern::glob(p).unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn empty_filter_keeps_everything() {
+        let filter = Filter::default();
+        assert!(filter.is_match(Path::new("/Movies/Foo/foo.mkv")));
+    }
+
+    #[test]
+    fn exclude_extension() {
+        let filter = filter(&[], &["**/*.nfo", "**/*.srt"]);
+
+        assert!(filter.is_match(Path::new("/Movies/Foo/foo.mkv")));
+        assert!(!filter.is_match(Path::new("/Movies/Foo/foo.nfo")));
+        assert!(!filter.is_match(Path::new("/Movies/Foo/foo.en.srt")));
+    }
+
+    #[test]
+    fn exclude_folder() {
+        let filter = filter(&[], &["/.trash", "/.trash/**", "/Downloads/incomplete/**"]);
+
+        assert!(!filter.is_match(Path::new("/.trash")));
+        assert!(!filter.is_match(Path::new("/.trash/foo.mkv")));
+        assert!(!filter.is_match(Path::new("/Downloads/incomplete/foo/foo.mkv")));
+        assert!(filter.is_match(Path::new("/Downloads/complete/foo.mkv")));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let filter = filter(&["/Movies/**", "/TV/**"], &["**/Extras/**"]);
+
+        assert!(filter.is_match(Path::new("/Movies/Foo/foo.mkv")));
+        assert!(filter.is_match(Path::new("/TV/Bar/Season 1/bar.mkv")));
+        assert!(!filter.is_match(Path::new("/Music/Baz/baz.flac")));
+        assert!(!filter.is_match(Path::new("/Movies/Foo/Extras/trailer.mkv")));
+    }
+
+    #[test]
+    fn glob_star_does_not_cross_folders() {
+        let filter = filter(&["/Movies/*"], &[]);
+
+        assert!(filter.is_match(Path::new("/Movies/Foo")));
+        assert!(!filter.is_match(Path::new("/Movies/Foo/foo.mkv")));
+    }
+
+    #[test]
+    fn regex_pattern() {
+        let filter = Filter {
+            include: Vec::new(),
+            exclude: vec![Pattern::Regex(Regex::new(r"(?i)\.(nfo|srt)$").unwrap())],
+        };
+
+        assert!(!filter.is_match(Path::new("/Movies/Foo/foo.NFO")));
+        assert!(filter.is_match(Path::new("/Movies/Foo/foo.mkv")));
+    }
+}
@@ -0,0 +1,230 @@
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/drive.readonly";
+const DRIVES_URL: &str = "https://www.googleapis.com/drive/v3/drives";
+
+#[derive(Debug, Error)]
+pub enum GoogleError {
+    #[error("could not read the Service Account key file")]
+    Io(#[from] std::io::Error),
+    #[error("invalid Service Account key file")]
+    Key(#[from] serde_json::Error),
+    #[error("could not sign the access token request")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("request to Google failed")]
+    Request(#[from] reqwest::Error),
+}
+
+type Result<T> = std::result::Result<T, GoogleError>;
+
+/// The fields of the Service Account key file needed to request an access token.
+#[derive(Deserialize)]
+struct Key {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+/// A Shared Drive visible to the Service Account.
+#[derive(Debug, Deserialize)]
+pub struct SharedDrive {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DriveList {
+    #[serde(default)]
+    drives: Vec<SharedDrive>,
+    next_page_token: Option<String>,
+}
+
+/// A minimal Google Drive client, used to list the Shared Drives of the Service Account,
+/// which Bernard cannot do. Everything else goes through Bernard, see [`crate::check`].
+pub(crate) struct Google {
+    client: Client,
+    token: String,
+}
+
+impl Key {
+    /// The signed assertion exchanged for an access token.
+    fn assertion(&self) -> Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        let claims = Claims {
+            iss: &self.client_email,
+            scope: SCOPE,
+            aud: &self.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+
+        Ok(jsonwebtoken::encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(self.private_key.as_bytes())?,
+        )?)
+    }
+}
+
+impl Google {
+    /// Request an access token for the Service Account, optionally through a proxy.
+    pub(crate) async fn connect(key_path: &Path, proxy: Option<&str>) -> Result<Self> {
+        let key: Key = serde_json::from_slice(&std::fs::read(key_path)?)?;
+        let assertion = key.assertion()?;
+
+        Self::exchange(client(proxy)?, &key.token_uri, &assertion).await
+    }
+
+    /// Exchange the signed assertion of the Service Account for an access token.
+    async fn exchange(client: Client, token_uri: &str, assertion: &str) -> Result<Self> {
+        let token: Token = client
+            .post(token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(Self {
+            client,
+            token: token.access_token,
+        })
+    }
+
+    /// All Shared Drives the Service Account is a member of.
+    pub(crate) async fn shared_drives(&self) -> Result<Vec<SharedDrive>> {
+        let mut drives = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .client
+                .get(DRIVES_URL)
+                .bearer_auth(&self.token)
+                .query(&[
+                    ("pageSize", "100"),
+                    ("fields", "nextPageToken,drives(id,name)"),
+                ]);
+
+            if let Some(page_token) = &page_token {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+
+            let list: DriveList = request.send().await?.error_for_status()?.json().await?;
+            drives.extend(list.drives);
+
+            match list.next_page_token {
+                Some(next) => page_token = Some(next),
+                None => break,
+            }
+        }
+
+        Ok(drives)
+    }
+}
+
+fn client(proxy: Option<&str>) -> Result<Client> {
+    let mut client = Client::builder();
+    if let Some(proxy) = proxy {
+        client = client.proxy(reqwest::Proxy::all(proxy)?);
+    }
+
+    Ok(client.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client, Google, GoogleError};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// The token endpoint is mocked, so the assertion does not have to be signed.
+    const ASSERTION: &str = "header.claims.signature";
+
+    #[tokio::test]
+    async fn token_exchange() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains(
+                "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer",
+            ))
+            .and(body_string_contains("assertion=header.claims.signature"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "secret",
+                "expires_in": 3600,
+                "token_type": "Bearer",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let token_uri = format!("{}/token", server.uri());
+        let result = Google::exchange(client(None).unwrap(), &token_uri, ASSERTION).await;
+
+        drop(server);
+        assert_eq!(result.unwrap().token, "secret");
+    }
+
+    /// The token is requested through the proxy, which receives the request for the original host.
+    #[tokio::test]
+    async fn token_exchange_through_proxy() {
+        let proxy = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "proxied",
+            })))
+            .expect(1)
+            .mount(&proxy)
+            .await;
+
+        let client = client(Some(&proxy.uri())).unwrap();
+        let result = Google::exchange(client, "http://oauth2.invalid/token", ASSERTION).await;
+
+        drop(proxy);
+        assert_eq!(result.unwrap().token, "proxied");
+    }
+
+    /// A key file without a valid private key fails before any request is made.
+    #[tokio::test]
+    async fn invalid_private_key() {
+        let path = crate::test_dir("google").join("account.json");
+        let key = json!({
+            "client_email": "a-train@example.iam.gserviceaccount.com",
+            "private_key": "not a private key",
+            "token_uri": "http://oauth2.invalid/token",
+        });
+        std::fs::write(&path, key.to_string()).unwrap();
+
+        let result = Google::connect(&path, None).await;
+        assert!(matches!(result, Err(GoogleError::Jwt(_))));
+    }
+}
@@ -1,76 +1,379 @@
-use autoscan::{Autoscan, AutoscanBuilder};
-use bernard::{Bernard, BernardBuilder};
-use reqwest::IntoUrl;
+use accounts::{Account, Accounts};
+use bernard::Bernard;
+use collapse::Collapse;
+use config::{DebounceConfig, Drive, FailureConfig, ServerConfig, Source};
+use debounce::Debouncer;
+use dry_run::DryRun;
+use filter::Filter;
+use outbox::Outbox;
+use rewrite::Rewrite;
+use schedule::Scheduler;
+use status::Status;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use target::{Target, TargetBuilder};
 use thiserror::Error;
+use tokio::sync::{mpsc, watch, Mutex as AsyncMutex};
+use tokio::time::Instant;
+use tracing::warn;
+use trigger::{SyncRequest, Trigger};
 
+mod accounts;
 mod autoscan;
+mod check;
+mod collapse;
 mod config;
+mod debounce;
+mod deliver;
 mod drive;
+mod dry_run;
+mod filter;
+mod google;
+mod metrics;
+mod outbox;
+mod overlay;
+mod reload;
+mod retry;
+mod rewrite;
+mod schedule;
+mod server;
+mod spans;
+mod status;
+mod target;
+mod template;
+mod trigger;
+mod webhook;
 
-pub use config::Config;
+pub use check::{check, shared_drives, Check};
+pub use config::{Config, ConfigError, Location};
+pub use google::SharedDrive;
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("Autoscan is unavailable")]
-    AutoscanUnavailable(#[from] autoscan::AutoscanError),
+    #[error("Target is unavailable")]
+    TargetUnavailable(#[from] target::TargetError),
+    #[error("Invalid target configuration")]
+    InvalidTarget(#[source] target::TargetError),
     #[error("Bernard")]
     Bernard(#[from] bernard::Error),
     #[error(transparent)]
     Unexpected(#[from] eyre::Report),
     #[error("Invalid configuration")]
     Configuration(#[from] config::ConfigError),
+    #[error("Outbox")]
+    Outbox(#[from] outbox::OutboxError),
+    #[error("Could not synchronise drives: {}", .0.join(", "))]
+    Drives(Vec<String>),
+    #[error("Drive {0} failed to synchronise {1} consecutive times")]
+    TooManyFailures(String, u32),
+    #[error("HTTP server")]
+    Server(#[from] hyper::Error),
+    #[error("Could not write dry run output")]
+    DryRun(#[from] std::io::Error),
+    #[error("Google Drive")]
+    Google(#[from] google::GoogleError),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct Atrain {
-    autoscan: Autoscan,
-    bernard: Bernard,
-    drives: Vec<String>,
+    /// The Service Accounts are only read at startup.
+    accounts: Accounts,
+    /// Changes held back until their folders are quiet, kept across reloads.
+    debouncer: Mutex<Debouncer>,
+    dry_run: Option<DryRun>,
+    outbox: Outbox,
+    proxy: Option<String>,
+    /// Notified after every reload, so the schedule is recalculated.
+    reloaded: watch::Sender<()>,
+    scheduler: Mutex<Scheduler>,
+    server: Option<ServerConfig>,
+    settings: RwLock<Arc<Settings>>,
+    source: Source,
+    /// Set once a shutdown has been requested.
+    shutdown: watch::Sender<bool>,
+    status: Status,
+    sync_requests: AsyncMutex<mpsc::Receiver<SyncRequest>>,
+    trigger: Trigger,
+}
+
+/// Everything taken from the configuration which can be replaced while running.
+///
+/// A sync uses the same settings from start to finish, even when the configuration is reloaded in the meantime.
+#[derive(Default)]
+pub(crate) struct Settings {
+    collapse: Collapse,
+    debounce: Option<DebounceConfig>,
+    drives: HashMap<String, Drive>,
+    failure: FailureConfig,
+    filter: Filter,
+    rewrite: Vec<Rewrite>,
+    targets: Vec<Target>,
+}
+
+impl Settings {
+    fn new(config: Config, proxy: Option<&str>) -> Result<Self> {
+        let autoscan = config.autoscan.into_iter().map(TargetBuilder::new);
+        let webhooks = config.webhook.into_iter().map(TargetBuilder::webhook);
+
+        let targets = autoscan
+            .chain(webhooks)
+            .map(|mut target| {
+                if let Some(proxy) = proxy {
+                    target = target.proxy(proxy);
+                }
+
+                target.build()
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map_err(Error::InvalidTarget)?;
+
+        Ok(Self {
+            collapse: config.collapse,
+            debounce: config.drive.debounce,
+            drives: config
+                .drive
+                .enabled()
+                .map(|drive| (drive.id.clone(), drive.clone()))
+                .collect(),
+            failure: config.drive.failure(),
+            filter: config.filter,
+            rewrite: config.rewrite,
+            targets,
+        })
+    }
 }
 
 impl Atrain {
+    /// Without any settings, until the first reload.
+    fn new(
+        accounts: Accounts,
+        dry_run: Option<DryRun>,
+        outbox: Outbox,
+        proxy: Option<String>,
+        server: Option<ServerConfig>,
+        source: Source,
+    ) -> Self {
+        let (trigger, sync_requests) = trigger::channel();
+
+        Self {
+            accounts,
+            debouncer: Mutex::default(),
+            dry_run,
+            outbox,
+            proxy,
+            reloaded: watch::channel(()).0,
+            scheduler: Mutex::new(Scheduler::new(Vec::new(), Instant::now())),
+            server,
+            settings: RwLock::default(),
+            shutdown: watch::channel(false).0,
+            source,
+            status: Status::new(Vec::new(), Vec::new()),
+            sync_requests: AsyncMutex::new(sync_requests),
+            trigger,
+        }
+    }
+
+    /// The current settings, which stay the same for as long as the returned value is held.
+    pub(crate) fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Synchronise the drives which are due and wait until the next drive is due,
+    /// until a sync is requested manually, or until the configuration is reloaded.
+    ///
+    /// Once a shutdown has been requested, a sync in progress is finished but no new syncs are started.
     pub async fn tick(&self) -> Result<()> {
-        use tokio::time::{sleep, Duration};
+        use tokio::time::sleep_until;
+
+        if self.is_shutting_down() {
+            return Ok(());
+        }
+
+        let settings = self.settings();
+        let due = self.scheduler.lock().unwrap().due(Instant::now());
+        let results = self.sync_drives(&settings, &due).await;
+        self.reschedule(&settings, results)?;
+
+        // Subscribe before reading the schedule, so a reload in between is not missed.
+        let mut reloaded = self.reloaded.subscribe();
+        let next = self.scheduler.lock().unwrap().next_due();
+        let sleep = async {
+            match next {
+                Some(next) => sleep_until(next).await,
+                // Without any drives there is nothing left to schedule.
+                None => futures::future::pending().await,
+            }
+        };
+
+        let mut sync_requests = self.sync_requests.lock().await;
+
+        tokio::select! {
+            _ = sleep => Ok(()),
+            _ = self.shutdown_requested() => Ok(()),
+            _ = reloaded.changed() => Ok(()),
+            Some(request) = sync_requests.recv() => self.handle_sync_request(request).await,
+        }
+    }
+
+    /// Stop scheduling new syncs, letting the sync in progress finish.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
 
-        self.sync().await?;
-        sleep(Duration::from_secs(60)).await;
-        Ok(())
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    async fn shutdown_requested(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Serve the HTTP endpoints, if enabled.
+    /// Never completes when the server is disabled.
+    pub async fn serve(&self) -> Result<()> {
+        match &self.server {
+            Some(server) => {
+                let status = self.status.clone();
+                let trigger = self.trigger.clone();
+                Ok(server::serve(server.address, status, trigger).await?)
+            }
+            None => futures::future::pending().await,
+        }
     }
 }
 
 pub struct AtrainBuilder {
-    autoscan: AutoscanBuilder,
-    bernard: BernardBuilder,
-    drives: Vec<String>,
+    /// The key file and key of every Service Account.
+    accounts: Vec<(PathBuf, bernard::Account)>,
+    config: Config,
+    database_path: PathBuf,
+    dry_run: Option<Option<PathBuf>>,
+    proxy: Option<String>,
 }
 
 impl AtrainBuilder {
-    pub fn new(config: Config, database_path: &str) -> Result<AtrainBuilder> {
-        let account = config.account()?;
+    /// A relative database path is relative to the current working directory.
+    pub fn new<P: AsRef<Path>>(config: Config, database_path: P) -> Result<AtrainBuilder> {
+        let database_path = database_path.as_ref().to_owned();
+
+        let accounts = config
+            .drive
+            .account
+            .iter()
+            .cloned()
+            .zip(config.accounts()?)
+            .collect();
 
         Ok(Self {
-            autoscan: Autoscan::builder(config.autoscan.url, config.autoscan.authentication),
-            bernard: Bernard::builder(database_path, account),
-            drives: config.drive.drives,
+            accounts,
+            config,
+            database_path,
+            dry_run: None,
+            proxy: None,
         })
     }
 
-    pub fn proxy<U: IntoUrl + Clone>(mut self, url: U) -> Self {
-        self.autoscan = self.autoscan.proxy(url.clone());
-        self.bernard = self.bernard.proxy(url);
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_owned());
+        self
+    }
+
+    /// Write the payloads to the given file, or stdout, instead of sending them to Autoscan.
+    pub fn dry_run(mut self, output: Option<PathBuf>) -> Self {
+        self.dry_run = Some(output);
         self
     }
 
     pub async fn build(self) -> Result<Atrain> {
-        let a_train = Atrain {
-            autoscan: self.autoscan.build(),
-            bernard: self.bernard.build().await.unwrap(),
-            drives: self.drives,
+        // A dry run works on a copy of the database and a throwaway outbox,
+        // so the progress of every drive is left untouched.
+        let mut dry_run = self
+            .dry_run
+            .map(|output| DryRun::new(output.as_deref()))
+            .transpose()?;
+        let (database_path, outbox) = match &mut dry_run {
+            Some(dry_run) => (
+                dry_run.copy_database(&self.database_path)?,
+                Outbox::memory().await?,
+            ),
+            None => (
+                self.database_path.clone(),
+                Outbox::open(&outbox_path(&self.database_path)).await?,
+            ),
         };
+        let database = database_path.to_string_lossy();
+
+        let mut accounts = Vec::new();
+        for (path, account) in self.accounts {
+            let mut bernard = Bernard::builder(&database, account);
+            if let Some(url) = &self.proxy {
+                bernard = bernard.proxy(url);
+            }
+
+            accounts.push(Account::new(path, bernard.build().await?));
+        }
+
+        let a_train = Atrain::new(
+            Accounts::new(accounts),
+            dry_run,
+            outbox,
+            self.proxy,
+            self.config.server.clone(),
+            self.config.source.clone(),
+        );
+
+        a_train.reload(self.config).await?;
+        a_train.restore_held().await?;
+
+        // A dry run should not touch any target at all.
+        if a_train.dry_run.is_some() {
+            return Ok(a_train);
+        }
+
+        // An unavailable target receives its changes from the outbox once it is back.
+        for target in &a_train.settings().targets {
+            let result = target.available().await;
+            if let Err(err) = &result {
+                warn!(target = %target.name, error = %status::error_chain(err), "Target is unavailable, its changes are kept in the outbox until it is back.");
+            }
+
+            a_train
+                .status
+                .target(&target.name, result.as_ref().err().map(|err| err as _));
+        }
 
-        // Check whether Autoscan is available.
-        a_train.autoscan.available().await?;
         Ok(a_train)
     }
 }
+
+/// The outbox is stored next to the database, e.g. `a-train.outbox.db` for `a-train.db`.
+fn outbox_path(database_path: &Path) -> PathBuf {
+    database_path.with_extension("outbox.db")
+}
+
+/// A new, empty directory for the files of a single test, unique across concurrent test runs.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "a-train-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -1,7 +1,11 @@
-use a_train::Config;
-use clap::Parser;
+use a_train::{Atrain, AtrainBuilder, Config};
+use clap::{Parser, Subcommand};
+use futures::future;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::signal::ctrl_c;
+use tracing::{info, warn};
 use tracing_subscriber::fmt::format::FmtSpan;
 
 // Use Jemalloc only for musl 64 bits platforms.
@@ -16,16 +20,57 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 #[clap(name = "A-Train", version = version())]
 struct Opt {
     /// Path to the configuration file
-    #[clap(short, long, value_name = "FILE", default_value = "a-train.toml")]
+    #[clap(
+        short,
+        long,
+        value_name = "FILE",
+        default_value = "a-train.toml",
+        global = true
+    )]
     config: String,
 
-    /// Path to the database file
-    #[clap(long, alias = "db", value_name = "FILE", default_value = "a-train.db")]
-    database: String,
+    /// Only use the ATRAIN_ environment variables and --set, ignoring --config
+    #[clap(long, global = true)]
+    no_config: bool,
+
+    /// Path to the database file [default: a-train.db next to the configuration file]
+    #[clap(long, alias = "db", value_name = "FILE", global = true)]
+    database: Option<PathBuf>,
 
     /// Proxy URL to use for debugging
-    #[clap(short, long, value_name = "URL")]
+    #[clap(short, long, value_name = "URL", global = true)]
     proxy: Option<String>,
+
+    /// Print the changes instead of sending them to Autoscan, only for run and sync-once
+    #[clap(long, global = true)]
+    dry_run: bool,
+
+    /// Append the changes of a dry run to this file instead of printing them (implies --dry-run)
+    #[clap(long, value_name = "FILE", global = true)]
+    dry_run_output: Option<PathBuf>,
+
+    /// Override a value of the configuration file, e.g. `--set drive.interval=30`
+    #[clap(long = "set", value_name = "KEY=VALUE", global = true)]
+    overrides: Vec<String>,
+
+    /// Seconds to wait for syncs in progress when shutting down
+    #[clap(long, value_name = "SECONDS", default_value = "10", global = true)]
+    shutdown_timeout: u64,
+
+    #[clap(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Keep synchronising the drives until stopped (default)
+    Run,
+    /// Validate the configuration, the Service Account, Autoscan and access to every drive
+    Check,
+    /// Synchronise every drive once and exit
+    SyncOnce,
+    /// List the Shared Drives the Service Account has access to
+    Drives,
 }
 
 fn version() -> &'static str {
@@ -44,25 +89,175 @@ async fn main() -> eyre::Result<()> {
         )
         .with_span_events(FmtSpan::CLOSE)
         .pretty()
+        // Keep stdout for output, such as the changes of a dry run.
+        .with_writer(std::io::stderr)
         .init();
 
-    let config = Config::new(&opt.config)?;
+    let dry_run = opt.dry_run || opt.dry_run_output.is_some();
+    if dry_run && matches!(opt.cmd, Some(Command::Check) | Some(Command::Drives)) {
+        eyre::bail!("--dry-run and --dry-run-output only apply to run and sync-once");
+    }
+
+    let config = match opt.no_config {
+        true => Config::from_overrides(&opt.overrides)?,
+        false => Config::with_overrides(&opt.config, &opt.overrides)?,
+    };
+
+    match opt.cmd {
+        None | Some(Command::Run) => {
+            let shutdown_timeout = Duration::from_secs(opt.shutdown_timeout);
+            run(build(config, opt).await?, shutdown_timeout).await
+        }
+        Some(Command::SyncOnce) => sync_once(build(config, opt).await?).await,
+        Some(Command::Check) => {
+            let database = database(&config, &opt);
+            check(config, &database, opt.proxy.as_deref()).await
+        }
+        Some(Command::Drives) => drives(config, opt.proxy.as_deref()).await,
+    }
+}
 
-    let mut a_train = a_train::AtrainBuilder::new(config, &opt.database)?;
-    if let Some(url) = opt.proxy {
+/// Paths given on the command line are relative to the current working directory, like any other command.
+fn database(config: &Config, opt: &Opt) -> PathBuf {
+    opt.database
+        .clone()
+        .unwrap_or_else(|| config.resolve("a-train.db"))
+}
+
+async fn build(config: Config, opt: Opt) -> eyre::Result<Atrain> {
+    let database = database(&config, &opt);
+    let dry_run_output = opt.dry_run_output;
+
+    let mut a_train = AtrainBuilder::new(config, database)?;
+    if let Some(url) = &opt.proxy {
         a_train = a_train.proxy(url);
     }
 
-    let a_train = a_train.build().await?;
+    if opt.dry_run || dry_run_output.is_some() {
+        a_train = a_train.dry_run(dry_run_output);
+    }
+
+    Ok(a_train.build().await?)
+}
+
+async fn run(a_train: Atrain, shutdown_timeout: Duration) -> eyre::Result<()> {
+    let result = run_until_shutdown(&a_train, shutdown_timeout).await;
+
+    // Held back changes are sent and the database is closed, even when stopping because of an error.
+    a_train.close().await;
+
+    result
+}
+
+async fn run_until_shutdown(a_train: &Atrain, shutdown_timeout: Duration) -> eyre::Result<()> {
+    // Redeliver changes which could not be sent to Autoscan earlier.
+    let outbox = a_train.run_outbox();
+    let reload = a_train.run_reload();
+    let server = a_train.serve();
+    let shutdown = shutdown_signal();
+    tokio::pin!(outbox, reload, server, shutdown);
 
     loop {
+        let tick = a_train.tick();
+        tokio::pin!(tick);
+
         tokio::select! {
-            result = a_train.tick() => result?,
-            _ = ctrl_c() => break,
+            result = &mut tick => result?,
+            result = &mut server => result?,
+            _ = &mut outbox => (),
+            _ = &mut reload => (),
+            _ = &mut shutdown => {
+                info!("Shutting down, waiting for syncs and redeliveries in progress to finish.");
+                a_train.shutdown();
+
+                // Undelivered changes are kept in the outbox and sent after the next start.
+                let finished = future::join(&mut tick, &mut outbox);
+                match tokio::time::timeout(shutdown_timeout, finished).await {
+                    Ok((result, ())) => result?,
+                    Err(_) => warn!("Syncs did not finish within {:?}, shutting down anyway.", shutdown_timeout),
+                }
+
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Resolves once SIGINT (Ctrl+C) or SIGTERM is received.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if ctrl_c().await.is_err() {
+            future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => future::pending().await,
         }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => (),
+        _ = terminate => (),
     }
+}
+
+/// Redeliver the outbox and synchronise every drive once, e.g. when run from cron.
+async fn sync_once(a_train: Atrain) -> eyre::Result<()> {
+    let result = async {
+        a_train.redeliver().await?;
+        a_train.sync().await
+    }
+    .await;
 
     a_train.close().await;
 
+    Ok(result?)
+}
+
+async fn check(config: Config, database: &Path, proxy: Option<&str>) -> eyre::Result<()> {
+    let checks = a_train::check(config, database, proxy).await;
+
+    let mut failed = 0;
+    for check in &checks {
+        match &check.result {
+            Ok(details) => println!("✓ {}: {}", check.name, details),
+            Err(reason) => {
+                failed += 1;
+                println!("✗ {}: {}", check.name, reason);
+            }
+        }
+    }
+
+    if failed > 0 {
+        eyre::bail!("{} of {} checks failed", failed, checks.len());
+    }
+
+    Ok(())
+}
+
+async fn drives(config: Config, proxy: Option<&str>) -> eyre::Result<()> {
+    let configured: Vec<_> = config.drive_ids().collect();
+
+    for drive in a_train::shared_drives(&config, proxy).await? {
+        let marker = if configured.contains(&drive.id.as_str()) {
+            "*"
+        } else {
+            " "
+        };
+
+        println!("{} {}  {}", marker, drive.id, drive.name);
+    }
+
     Ok(())
 }
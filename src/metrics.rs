@@ -0,0 +1,60 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+
+pub(crate) static SYNC_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "atrain_sync_duration_seconds",
+        "Duration of the sync of a Shared Drive.",
+        &["drive"]
+    )
+    .unwrap()
+});
+
+pub(crate) static SYNCS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "atrain_syncs_total",
+        "Number of syncs per Shared Drive by kind (full, partial or failed).",
+        &["drive", "kind"]
+    )
+    .unwrap()
+});
+
+pub(crate) static PARTIAL_CHANGE_LISTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "atrain_partial_change_lists_total",
+        "Number of Partial Change Lists encountered per Shared Drive.",
+        &["drive"]
+    )
+    .unwrap()
+});
+
+pub(crate) static PAYLOAD_PATHS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "atrain_payload_paths",
+        "Number of created or deleted folders (created, deleted) and files (created_paths, deleted_paths) in each payload.",
+        &["drive", "change"],
+        vec![1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0]
+    )
+    .unwrap()
+});
+
+pub(crate) static TARGET_REQUESTS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "atrain_target_request_duration_seconds",
+        "Latency of the requests sending changes to Autoscan or a webhook by target and status code.",
+        &["target", "status"]
+    )
+    .unwrap()
+});
+
+/// Encode all metrics in the Prometheus text format.
+pub(crate) fn encode() -> prometheus::Result<(String, Vec<u8>)> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+    Ok((encoder.format_type().to_owned(), buffer))
+}
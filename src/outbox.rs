@@ -0,0 +1,510 @@
+use crate::{autoscan::Payload, debounce::Held};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions};
+use std::{collections::HashSet, path::Path, str::FromStr, sync::Mutex};
+use thiserror::Error;
+use tracing::error;
+
+#[derive(Debug, Error)]
+pub enum OutboxError {
+    #[error("database error")]
+    Database(#[from] sqlx::Error),
+    #[error("invalid payload")]
+    Payload(#[from] serde_json::Error),
+}
+
+type Result<T> = std::result::Result<T, OutboxError>;
+
+/// A payload waiting to be delivered to a target.
+#[derive(Debug)]
+pub(crate) struct Entry {
+    pub(crate) id: i64,
+    pub(crate) target: String,
+    pub(crate) trigger: String,
+    pub(crate) drive_id: String,
+    pub(crate) payload: Payload,
+}
+
+/// Persists payloads until they have been delivered,
+/// so changes are not lost when a target is unavailable.
+///
+/// The changes held back by the debounce are stored here as well, so they survive a restart or crash.
+pub(crate) struct Outbox {
+    pool: SqlitePool,
+    /// Entries currently being delivered, which should not be redelivered.
+    in_flight: Mutex<HashSet<i64>>,
+}
+
+impl Outbox {
+    pub(crate) async fn open(path: &Path) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        Self::connect(options, SqlitePoolOptions::new()).await
+    }
+
+    /// An outbox which is gone once closed, e.g. for a dry run.
+    pub(crate) async fn memory() -> Result<Self> {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")?;
+
+        // The database is gone with its connection, so the connection is kept for as long as the outbox.
+        let pool = SqlitePoolOptions::new()
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+
+        Self::connect(options, pool).await
+    }
+
+    async fn connect(options: SqliteConnectOptions, pool: SqlitePoolOptions) -> Result<Self> {
+        let pool = pool
+            .max_connections(1)
+            // A new connection might be to a new, empty database, e.g. in memory.
+            .after_connect(|connection| Box::pin(create_tables(connection)))
+            .connect_with(options)
+            .await?;
+
+        Ok(Self {
+            pool,
+            in_flight: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Store the payload and mark it as in flight.
+    pub(crate) async fn push(
+        &self,
+        target: &str,
+        trigger: &str,
+        drive_id: &str,
+        payload: &Payload,
+    ) -> Result<i64> {
+        let payload = serde_json::to_string(payload)?;
+
+        let id = sqlx::query(
+            "INSERT INTO outbox (target, trigger, drive_id, payload) VALUES (?, ?, ?, ?)",
+        )
+        .bind(target)
+        .bind(trigger)
+        .bind(drive_id)
+        .bind(payload)
+        .execute(&self.pool)
+        .await?
+        .last_insert_rowid();
+
+        self.in_flight.lock().unwrap().insert(id);
+        Ok(id)
+    }
+
+    /// Store every payload, such as the chunks of a payload, and mark them as in flight.
+    ///
+    /// When a payload cannot be stored, the payloads stored before it are released,
+    /// so they are picked up by [`Outbox::pending`] instead of staying in flight forever.
+    pub(crate) async fn push_all(
+        &self,
+        target: &str,
+        trigger: &str,
+        drive_id: &str,
+        payloads: &[Payload],
+    ) -> Result<Vec<i64>> {
+        let mut ids = Vec::with_capacity(payloads.len());
+
+        for payload in payloads {
+            match self.push(target, trigger, drive_id, payload).await {
+                Ok(id) => ids.push(id),
+                Err(err) => {
+                    for id in ids {
+                        self.release(id);
+                    }
+
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Remove a delivered payload.
+    pub(crate) async fn remove(&self, id: i64) -> Result<()> {
+        let result = sqlx::query("DELETE FROM outbox WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await;
+
+        self.release(id);
+        result?;
+        Ok(())
+    }
+
+    /// Allow a payload which could not be delivered to be picked up by [`Outbox::pending`].
+    pub(crate) fn release(&self, id: i64) {
+        self.in_flight.lock().unwrap().remove(&id);
+    }
+
+    /// All payloads awaiting delivery which are not in flight, oldest first.
+    /// The returned entries are marked as in flight.
+    ///
+    /// Payloads which cannot be read are logged and quarantined instead of blocking the others.
+    pub(crate) async fn pending(&self) -> Result<Vec<Entry>> {
+        let rows: Vec<(i64, String, String, String, String)> = sqlx::query_as(
+            "SELECT id, target, trigger, drive_id, payload FROM outbox ORDER BY id ASC",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut entries = Vec::new();
+        let mut invalid = Vec::new();
+
+        {
+            let in_flight = self.in_flight.lock().unwrap();
+
+            for (id, target, trigger, drive_id, payload) in rows {
+                if in_flight.contains(&id) {
+                    continue;
+                }
+
+                match serde_json::from_str(&payload) {
+                    Ok(payload) => entries.push(Entry {
+                        id,
+                        target,
+                        trigger,
+                        drive_id,
+                        payload,
+                    }),
+                    Err(err) => invalid.push((id, err)),
+                }
+            }
+        }
+
+        for (id, err) in invalid {
+            error!(id, error = %err, "Quarantining changes which cannot be read from the outbox.");
+            self.quarantine(&[id], &err.to_string()).await?;
+        }
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Another caller might have claimed some entries while quarantining.
+        entries.retain(|entry| !in_flight.contains(&entry.id));
+        in_flight.extend(entries.iter().map(|entry| entry.id));
+
+        Ok(entries)
+    }
+
+    /// Quarantine every payload which is not in flight and is meant for a target other than the given targets,
+    /// returning the number of payloads quarantined.
+    pub(crate) async fn retain_targets(&self, targets: &[&str]) -> Result<usize> {
+        let rows: Vec<(i64, String)> = sqlx::query_as("SELECT id, target FROM outbox")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let ids: Vec<_> = {
+            let in_flight = self.in_flight.lock().unwrap();
+            rows.into_iter()
+                .filter(|(id, target)| {
+                    !targets.contains(&target.as_str()) && !in_flight.contains(id)
+                })
+                .map(|(id, _)| id)
+                .collect()
+        };
+
+        self.quarantine(&ids, "unknown target").await?;
+        Ok(ids.len())
+    }
+
+    /// Move the payloads to the quarantine, in a single transaction.
+    async fn quarantine(&self, ids: &[i64], reason: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for &id in ids {
+            sqlx::query(
+                "INSERT OR REPLACE INTO outbox_quarantine
+                    SELECT id, target, trigger, drive_id, payload, created_at, ? FROM outbox WHERE id = ?",
+            )
+            .bind(reason)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query("DELETE FROM outbox WHERE id = ?")
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Store the changes held back for the drive, replacing those stored before,
+    /// or forget them when nothing is held back anymore.
+    pub(crate) async fn hold(&self, drive_id: &str, held: Option<&Held>) -> Result<()> {
+        match held {
+            Some(held) => {
+                sqlx::query("INSERT OR REPLACE INTO debounce (drive_id, held) VALUES (?, ?)")
+                    .bind(drive_id)
+                    .bind(serde_json::to_string(held)?)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM debounce WHERE drive_id = ?")
+                    .bind(drive_id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The changes held back for every drive.
+    ///
+    /// Changes which cannot be read are logged and forgotten, as they cannot be sent either.
+    pub(crate) async fn held(&self) -> Result<Vec<(String, Held)>> {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT drive_id, held FROM debounce ORDER BY drive_id ASC")
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut held = Vec::with_capacity(rows.len());
+        for (drive_id, json) in rows {
+            match serde_json::from_str(&json) {
+                Ok(changes) => held.push((drive_id, changes)),
+                Err(err) => {
+                    error!(drive_id = %drive_id, error = %err, "Dropping held back changes which cannot be read.");
+                    self.hold(&drive_id, None).await?;
+                }
+            }
+        }
+
+        Ok(held)
+    }
+
+    pub(crate) async fn close(&self) {
+        self.pool.close().await
+    }
+}
+
+async fn create_tables(connection: &mut SqliteConnection) -> sqlx::Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            target TEXT NOT NULL,
+            trigger TEXT NOT NULL,
+            drive_id TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    // Payloads which can never be delivered are kept aside for inspection.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS outbox_quarantine (
+            id INTEGER PRIMARY KEY,
+            target TEXT NOT NULL,
+            trigger TEXT NOT NULL,
+            drive_id TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            reason TEXT NOT NULL
+        )",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS debounce (
+            drive_id TEXT PRIMARY KEY,
+            held TEXT NOT NULL,
+            updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outbox;
+    use crate::autoscan::Payload;
+    use pretty_assertions::assert_eq;
+    use sqlx::Connection;
+
+    async fn outbox() -> Outbox {
+        Outbox::memory().await.unwrap()
+    }
+
+    /// A replaced connection, e.g. once the pool closed an expired one, still finds the tables.
+    #[tokio::test]
+    async fn reconnected_outbox_has_tables() {
+        let outbox = outbox().await;
+
+        // Close the connection without returning it, so the pool has to open a new one.
+        let connection = outbox.pool.acquire().await.unwrap().detach();
+        connection.close().await.unwrap();
+
+        outbox
+            .push("plex", "a-train", "drive", &Payload::default())
+            .await
+            .unwrap();
+        outbox.hold("drive", None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_flight_entries_are_not_pending() {
+        let outbox = outbox().await;
+
+        let id = outbox
+            .push("plex", "a-train", "drive", &Payload::default())
+            .await
+            .unwrap();
+        assert!(outbox.pending().await.unwrap().is_empty());
+
+        outbox.release(id);
+        let pending = outbox.pending().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, id);
+        assert_eq!(pending[0].target, "plex");
+        assert_eq!(pending[0].trigger, "a-train");
+        assert_eq!(pending[0].drive_id, "drive");
+
+        // Pending entries are in flight until released or removed.
+        assert!(outbox.pending().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn partially_stored_payloads_are_released() {
+        let outbox = outbox().await;
+
+        // Only the first payload can be stored.
+        sqlx::query(
+            "CREATE TRIGGER full BEFORE INSERT ON outbox
+            WHEN (SELECT COUNT(*) FROM outbox) > 0
+            BEGIN SELECT RAISE(ABORT, 'full'); END",
+        )
+        .execute(&outbox.pool)
+        .await
+        .unwrap();
+
+        let payloads = [Payload::default(), Payload::default()];
+        assert!(outbox
+            .push_all("plex", "a-train", "drive", &payloads)
+            .await
+            .is_err());
+
+        assert_eq!(outbox.pending().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn removed_entries_are_gone() {
+        let outbox = outbox().await;
+
+        let first = outbox
+            .push("plex", "a-train", "first", &Payload::default())
+            .await
+            .unwrap();
+        let second = outbox
+            .push("plex", "a-train", "second", &Payload::default())
+            .await
+            .unwrap();
+
+        outbox.remove(first).await.unwrap();
+        outbox.release(second);
+
+        let pending: Vec<_> = outbox
+            .pending()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.drive_id)
+            .collect();
+        assert_eq!(pending, vec!["second"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_entries_are_quarantined() {
+        let outbox = outbox().await;
+
+        sqlx::query("INSERT INTO outbox (target, trigger, drive_id, payload) VALUES ('plex', 'a-train', 'broken', '{')")
+            .execute(&outbox.pool)
+            .await
+            .unwrap();
+        let id = outbox
+            .push("plex", "a-train", "valid", &Payload::default())
+            .await
+            .unwrap();
+        outbox.release(id);
+
+        let pending = outbox.pending().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].drive_id, "valid");
+
+        let quarantined: Vec<(String, String)> =
+            sqlx::query_as("SELECT drive_id, reason FROM outbox_quarantine")
+                .fetch_all(&outbox.pool)
+                .await
+                .unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].0, "broken");
+    }
+
+    #[tokio::test]
+    async fn unknown_targets_are_quarantined() {
+        let outbox = outbox().await;
+
+        for target in &["plex", "emby"] {
+            let id = outbox
+                .push(target, "a-train", "drive", &Payload::default())
+                .await
+                .unwrap();
+            outbox.release(id);
+        }
+
+        assert_eq!(outbox.retain_targets(&["plex"]).await.unwrap(), 1);
+
+        let pending = outbox.pending().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].target, "plex");
+    }
+
+    #[tokio::test]
+    async fn held_changes_are_replaced() {
+        let outbox = outbox().await;
+        let mut debouncer = crate::debounce::Debouncer::default();
+        let now = tokio::time::Instant::now();
+        let wall = std::time::SystemTime::now();
+        let config = crate::config::DebounceConfig {
+            quiet: 60,
+            max_wait: 60,
+        };
+
+        for folder in ["/Movies/Foo", "/TV/Show"] {
+            let payload = Payload {
+                created: std::iter::once(folder.into()).collect(),
+                ..Default::default()
+            };
+            debouncer.push("drive", payload, Some(&config), now);
+
+            let held = debouncer.held("drive", now, wall);
+            outbox.hold("drive", held.as_ref()).await.unwrap();
+        }
+
+        sqlx::query("INSERT INTO debounce (drive_id, held) VALUES ('broken', '{')")
+            .execute(&outbox.pool)
+            .await
+            .unwrap();
+
+        let held = outbox.held().await.unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(
+            Some(&held[0].1),
+            debouncer.held("drive", now, wall).as_ref()
+        );
+
+        outbox.hold("drive", None).await.unwrap();
+        assert!(outbox.held().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+use crate::config::ConfigError;
+use std::path::PathBuf;
+use toml::Value;
+
+/// Environment variables starting with this prefix override the configuration file.
+const ENV_PREFIX: &str = "ATRAIN_";
+
+/// Keys of secrets ending with this suffix read their value from the file at the given path, see [`is_secret`].
+const FILE_SUFFIX: &str = "_file";
+
+/// A single value replacing, or adding to, the values of the configuration file.
+#[derive(Debug)]
+pub(crate) struct Override {
+    key: Vec<String>,
+    value: String,
+}
+
+impl Override {
+    /// Parse a `KEY=VALUE` command-line argument, e.g. `drive.interval=30`.
+    pub(crate) fn from_arg(arg: &str) -> Result<Self, ConfigError> {
+        let (key, value) = arg.split_once('=').ok_or_else(|| ConfigError::Override {
+            key: arg.to_owned(),
+            reason: "expected KEY=VALUE".to_owned(),
+        })?;
+
+        Ok(Self {
+            key: key.split('.').map(str::to_owned).collect(),
+            value: value.to_owned(),
+        })
+    }
+
+    /// All `ATRAIN_` variables, e.g. `ATRAIN_AUTOSCAN__URL` for `autoscan.url`,
+    /// sorted by name to apply them in a predictable order.
+    pub(crate) fn from_env<I>(vars: I) -> Vec<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut overrides: Vec<_> = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(ENV_PREFIX)?;
+
+                Some(Self {
+                    key: key.to_lowercase().split("__").map(str::to_owned).collect(),
+                    value,
+                })
+            })
+            .collect();
+
+        overrides.sort_by(|a, b| a.key.cmp(&b.key));
+        overrides
+    }
+
+    fn key(&self) -> String {
+        self.key.join(".")
+    }
+
+    /// The key as written in error messages, e.g. `autoscan[1].password` for `autoscan.1.password_file`.
+    pub(crate) fn path(&self) -> String {
+        let mut path = String::new();
+
+        for (i, segment) in self.key.iter().enumerate() {
+            let segment = match i + 1 == self.key.len() {
+                true => segment.strip_suffix(FILE_SUFFIX).unwrap_or(segment),
+                false => segment,
+            };
+
+            if segment.parse::<usize>().is_ok() {
+                path.push_str(&format!("[{}]", segment));
+            } else {
+                if i > 0 {
+                    path.push('.');
+                }
+                path.push_str(segment);
+            }
+        }
+
+        path
+    }
+
+    fn error(&self, reason: impl Into<String>) -> ConfigError {
+        ConfigError::Override {
+            key: self.key(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Set the value at the key, creating any missing tables along the way.
+    ///
+    /// Numeric segments index into arrays, e.g. `autoscan.1.url`.
+    pub(crate) fn apply(&self, root: &mut Value) -> Result<(), ConfigError> {
+        let (last, parents) = match self.key.split_last() {
+            Some((last, parents)) if !self.key.iter().any(String::is_empty) => (last, parents),
+            _ => return Err(self.error("empty key")),
+        };
+
+        let (last, secret) = match last.strip_suffix(FILE_SUFFIX) {
+            Some(last) if is_secret(parents, last) => (last, Some(self.secret()?)),
+            Some(_) => {
+                return Err(self.error("only passwords and header values can be read from a file"))
+            }
+            None => (last.as_str(), None),
+        };
+        let value = |existing: Option<&Value>| match &secret {
+            Some(secret) => Value::String(secret.clone()),
+            None => parse_value(&self.value, existing),
+        };
+
+        let mut current = root;
+        for segment in parents {
+            current = match current {
+                Value::Table(table) => table
+                    .entry(segment.as_str())
+                    .or_insert_with(|| Value::Table(Default::default())),
+                Value::Array(array) => element(array, segment)
+                    .ok_or_else(|| self.error(format!("no array element {:?}", segment)))?,
+                _ => return Err(self.error(format!("{:?} is not a table", segment))),
+            };
+        }
+
+        match current {
+            Value::Table(table) => {
+                let value = value(table.get(last));
+                table.insert(last.to_owned(), value);
+            }
+            Value::Array(array) => {
+                let element = element(array, last)
+                    .ok_or_else(|| self.error(format!("no array element {:?}", last)))?;
+                *element = value(Some(element));
+            }
+            _ => return Err(self.error("parent is not a table")),
+        }
+
+        Ok(())
+    }
+
+    /// Read a secret, such as a password, from a file.
+    fn secret(&self) -> Result<String, ConfigError> {
+        let path = PathBuf::from(&self.value);
+
+        match std::fs::read_to_string(&path) {
+            Ok(secret) => Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+            Err(source) => Err(ConfigError::Secret {
+                key: self.key(),
+                path,
+                source,
+            }),
+        }
+    }
+}
+
+/// Whether the key holds a secret: a password or the value of a webhook header.
+fn is_secret(parents: &[String], last: &str) -> bool {
+    last == "password" || matches!(parents.last(), Some(parent) if parent == "headers")
+}
+
+fn element<'a>(array: &'a mut [Value], index: &str) -> Option<&'a mut Value> {
+    array.get_mut(index.parse::<usize>().ok()?)
+}
+
+/// Values replacing a string are taken literally, e.g. a password of `1234`.
+/// Other values are interpreted as TOML, e.g. `30`, `true` or `["a", "b"]`,
+/// and fall back to a plain string otherwise.
+///
+/// A new value which should be a string despite looking like a number can be quoted, e.g. `"1234"`.
+fn parse_value(raw: &str, existing: Option<&Value>) -> Value {
+    if let Some(Value::String(_)) = existing {
+        return Value::String(raw.to_owned());
+    }
+
+    toml::from_str::<toml::value::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Override;
+    use pretty_assertions::assert_eq;
+    use toml::Value;
+
+    fn apply(source: &str, overrides: &[Override]) -> Value {
+        let mut value: Value = toml::from_str(source).unwrap();
+        for o in overrides {
+            o.apply(&mut value).unwrap();
+        }
+
+        value
+    }
+
+    #[test]
+    fn environment_variables() {
+        let overrides = Override::from_env(vec![
+            (
+                "ATRAIN_DRIVE__DRIVES".into(),
+                r#"["0A1xxxxxxxxxUk9PVA"]"#.into(),
+            ),
+            ("ATRAIN_AUTOSCAN__URL".into(), "http://autoscan:3030".into()),
+            ("ATRAIN_DRIVE__INTERVAL".into(), "30".into()),
+            ("HOME".into(), "/root".into()),
+        ]);
+
+        let value = apply(
+            r#"
+            [autoscan]
+            url = "http://localhost:3030"
+            "#,
+            &overrides,
+        );
+
+        assert_eq!(
+            value,
+            toml::from_str(
+                r#"
+                [autoscan]
+                url = "http://autoscan:3030"
+
+                [drive]
+                interval = 30
+                drives = ["0A1xxxxxxxxxUk9PVA"]
+                "#
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn arguments_index_arrays() {
+        let value = apply(
+            r#"
+            [[autoscan]]
+            url = "http://plex:3030"
+
+            [[autoscan]]
+            url = "http://emby:3030"
+            "#,
+            &[Override::from_arg("autoscan.1.url=http://jellyfin:3030").unwrap()],
+        );
+
+        assert_eq!(
+            value["autoscan"][0]["url"].as_str(),
+            Some("http://plex:3030")
+        );
+        assert_eq!(
+            value["autoscan"][1]["url"].as_str(),
+            Some("http://jellyfin:3030")
+        );
+
+        let mut value = value;
+        assert!(Override::from_arg("autoscan.2.url=http://emby:3030")
+            .unwrap()
+            .apply(&mut value)
+            .is_err());
+        assert!(Override::from_arg("autoscan.url").is_err());
+    }
+
+    #[test]
+    fn strings_stay_strings() {
+        let value = apply(
+            r#"
+            [autoscan]
+            password = "secret"
+            "#,
+            &Override::from_env(vec![
+                ("ATRAIN_AUTOSCAN__PASSWORD".into(), "1234".into()),
+                ("ATRAIN_AUTOSCAN__USERNAME".into(), r#""007""#.into()),
+                ("ATRAIN_DRIVE__INTERVAL".into(), "30".into()),
+            ]),
+        );
+
+        assert_eq!(value["autoscan"]["password"].as_str(), Some("1234"));
+        assert_eq!(value["autoscan"]["username"].as_str(), Some("007"));
+        assert_eq!(value["drive"]["interval"].as_integer(), Some(30));
+    }
+
+    #[test]
+    fn secrets_from_files() {
+        let path = crate::test_dir("overlay-secret").join("password");
+        std::fs::write(&path, "general kenobi\n").unwrap();
+
+        let value = apply(
+            "[autoscan]",
+            &Override::from_env(vec![(
+                "ATRAIN_AUTOSCAN__PASSWORD_FILE".into(),
+                path.to_string_lossy().into_owned(),
+            )]),
+        );
+
+        assert_eq!(
+            value["autoscan"]["password"].as_str(),
+            Some("general kenobi")
+        );
+    }
+
+    #[test]
+    fn only_secrets_from_files() {
+        let path = crate::test_dir("overlay-secret-only").join("secret");
+        std::fs::write(&path, "Bearer kenobi\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let value = apply(
+            "[[webhook]]",
+            &Override::from_env(vec![(
+                "ATRAIN_WEBHOOK__0__HEADERS__AUTHORIZATION_FILE".into(),
+                path.clone(),
+            )]),
+        );
+        assert_eq!(
+            value["webhook"][0]["headers"]["authorization"].as_str(),
+            Some("Bearer kenobi")
+        );
+
+        let mut value = Value::Table(Default::default());
+        for arg in &["autoscan.url_file", "drive.account_file"] {
+            let o = Override::from_arg(&format!("{}={}", arg, path)).unwrap();
+            assert!(o.apply(&mut value).is_err());
+        }
+    }
+}
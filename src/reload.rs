@@ -0,0 +1,176 @@
+use crate::{Atrain, Config, Result, Settings};
+use futures::{stream::BoxStream, StreamExt};
+use std::{path::Path, sync::Arc, time::SystemTime};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, warn};
+
+/// How often the configuration file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+impl Atrain {
+    /// Replace the drives, targets, filters and rewrite rules with those of the configuration.
+    ///
+    /// Drives which remain keep their schedule and status, and their progress is kept by Bernard.
+    /// Nothing changes when the configuration is invalid.
+    /// Changes held back for drives which are no longer configured are sent right away, see [`Atrain::send_held`].
+    /// Undelivered changes for targets which are no longer configured are quarantined.
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        if !config.drive.account.iter().eq(self.accounts.paths()) {
+            warn!("Changing the Service Accounts requires a restart.");
+        }
+
+        if config.server != self.server {
+            warn!("Changing the HTTP server requires a restart.");
+        }
+
+        let drives: Vec<_> = config
+            .drive
+            .enabled()
+            .zip(config.drive.intervals())
+            .map(|(drive, (id, interval))| (id, drive.display_name().to_owned(), interval))
+            .collect();
+        let assignments: Vec<_> = config
+            .drive
+            .enabled()
+            .map(|drive| {
+                let account = drive.account.as_deref().and_then(|account| {
+                    let index = self.accounts.paths().position(|path| path == account);
+                    if index.is_none() {
+                        warn!(drive = %drive.display_name(), ?account, "Ignoring the new Service Account of the drive until a restart.");
+                    }
+
+                    index
+                });
+
+                (drive.id.clone(), account)
+            })
+            .collect();
+        let targets: Vec<_> = config
+            .autoscan
+            .iter()
+            .map(|autoscan| autoscan.name().into_owned())
+            .chain(
+                config
+                    .webhook
+                    .iter()
+                    .map(|webhook| webhook.name().into_owned()),
+            )
+            .collect();
+
+        let settings = Settings::new(config, self.proxy.as_deref())?;
+
+        // Swap everything while holding the scheduler, so no sync is scheduled in between.
+        {
+            let mut scheduler = self.scheduler.lock().unwrap();
+            scheduler.update(
+                drives
+                    .iter()
+                    .map(|(id, _, interval)| (id.clone(), *interval)),
+                Instant::now(),
+            );
+            self.status.update(
+                drives
+                    .iter()
+                    .map(|(id, name, interval)| (id.as_str(), name.as_str(), *interval)),
+                targets.iter().map(String::as_str),
+            );
+            for (id, _, _) in &drives {
+                self.status.scheduled(id, scheduler.next(id));
+            }
+            self.accounts.assign(
+                assignments
+                    .iter()
+                    .map(|(id, account)| (id.as_str(), *account)),
+            );
+            *self.settings.write().unwrap() = Arc::new(settings);
+        }
+
+        // Wake up the scheduler, as drives might be due earlier or have been added.
+        self.reloaded.send_replace(());
+
+        // Syncs of a removed drive which are still running no longer hold back its changes, see `Atrain::sync_changes`.
+        let removed = {
+            let settings = self.settings();
+            self.debouncer
+                .lock()
+                .unwrap()
+                .drain_unless(|drive_id| settings.drives.contains_key(drive_id))
+        };
+        self.send_held(removed).await;
+
+        if self.dry_run.is_none() {
+            let targets: Vec<_> = targets.iter().map(String::as_str).collect();
+            match self.outbox.retain_targets(&targets).await {
+                Ok(0) => (),
+                Ok(count) => {
+                    warn!(count, "Quarantined undelivered changes for targets which are no longer configured.")
+                }
+                Err(err) => error!(error = %err, "Could not check the outbox for unknown targets."),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reload the configuration on SIGHUP or once the configuration file has changed.
+    ///
+    /// An invalid configuration is logged and ignored, leaving the current configuration in place.
+    pub async fn run_reload(&self) {
+        let path = self.source.path.as_deref();
+        let mut last_modified = modified(path);
+        let mut hangups = hangups();
+
+        loop {
+            let reason = tokio::select! {
+                Some(()) = hangups.next() => "SIGHUP",
+                _ = sleep(WATCH_INTERVAL) => {
+                    let current = modified(path);
+                    if current == last_modified {
+                        continue;
+                    }
+
+                    last_modified = current;
+                    "file change"
+                }
+            };
+
+            let result = match self.source.load() {
+                Ok(config) => self.reload(config).await,
+                Err(err) => Err(err.into()),
+            };
+
+            match result {
+                Ok(()) => info!(reason, "Reloaded the configuration."),
+                Err(err) => {
+                    error!(reason, error = ?err, "Invalid configuration, keeping the current configuration.")
+                }
+            }
+        }
+    }
+}
+
+/// Without a configuration file, only a SIGHUP reloads the overrides.
+fn modified(path: Option<&Path>) -> Option<SystemTime> {
+    std::fs::metadata(path?).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(unix)]
+fn hangups() -> BoxStream<'static, ()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::hangup()) {
+        Ok(signal) => futures::stream::unfold(signal, |mut signal| async move {
+            signal.recv().await.map(|()| ((), signal))
+        })
+        .boxed(),
+        Err(err) => {
+            warn!(error = %err, "Could not listen for SIGHUP.");
+            futures::stream::pending().boxed()
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn hangups() -> BoxStream<'static, ()> {
+    futures::stream::pending().boxed()
+}
@@ -0,0 +1,122 @@
+use backoff::{backoff::Backoff, ExponentialBackoff, SystemClock};
+use futures::future::BoxFuture;
+use reqwest::{Request, Response};
+use std::time::{Duration, Instant};
+use tower::retry::Policy;
+use tracing::debug;
+
+/// Retries requests failing with a server error, a connection error or a timeout,
+/// waiting a jittered, exponentially increasing interval in between.
+///
+/// Client errors (4xx) are never retried.
+/// Every request is retried according to its own [`Attempts`], see [`RetryPolicy::start`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RetryPolicy {
+    max_attempts: usize,
+    max_elapsed: Duration,
+    initial_interval: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(max_attempts: usize, max_elapsed: Duration) -> Self {
+        Self::with_initial_interval(max_attempts, max_elapsed, Duration::from_millis(500))
+    }
+
+    pub(crate) fn with_initial_interval(
+        max_attempts: usize,
+        max_elapsed: Duration,
+        initial_interval: Duration,
+    ) -> Self {
+        Self {
+            max_attempts,
+            max_elapsed,
+            initial_interval,
+        }
+    }
+
+    /// The attempts of a request starting now,
+    /// so the maximum elapsed time counts from the start of its first attempt.
+    pub(crate) fn start(&self) -> Attempts {
+        let backoff = ExponentialBackoff {
+            current_interval: self.initial_interval,
+            initial_interval: self.initial_interval,
+            start_time: Instant::now(),
+            max_elapsed_time: Some(self.max_elapsed),
+            ..ExponentialBackoff::default()
+        };
+
+        Attempts {
+            attempt: 1,
+            max_attempts: self.max_attempts,
+            backoff,
+        }
+    }
+}
+
+/// The state of the retries of a single request.
+#[derive(Debug)]
+pub(crate) struct Attempts {
+    attempt: usize,
+    max_attempts: usize,
+    backoff: ExponentialBackoff,
+}
+
+impl Attempts {
+    fn is_retryable(result: Result<&Response, &reqwest::Error>) -> bool {
+        match result {
+            Ok(response) => response.status().is_server_error(),
+            Err(err) => err.is_connect() || err.is_timeout(),
+        }
+    }
+}
+
+// The system clock of backoff does not implement Clone.
+impl Clone for Attempts {
+    fn clone(&self) -> Self {
+        let backoff = &self.backoff;
+
+        Self {
+            attempt: self.attempt,
+            max_attempts: self.max_attempts,
+            backoff: ExponentialBackoff {
+                current_interval: backoff.current_interval,
+                initial_interval: backoff.initial_interval,
+                randomization_factor: backoff.randomization_factor,
+                multiplier: backoff.multiplier,
+                max_interval: backoff.max_interval,
+                start_time: backoff.start_time,
+                max_elapsed_time: backoff.max_elapsed_time,
+                clock: SystemClock::default(),
+            },
+        }
+    }
+}
+
+impl Policy<Request, Response, reqwest::Error> for Attempts {
+    type Future = BoxFuture<'static, Self>;
+
+    fn retry(
+        &self,
+        _request: &Request,
+        result: Result<&Response, &reqwest::Error>,
+    ) -> Option<Self::Future> {
+        if !Self::is_retryable(result) || self.attempt >= self.max_attempts {
+            return None;
+        }
+
+        let mut attempts = self.clone();
+        let delay = attempts.backoff.next_backoff()?;
+        attempts.attempt += 1;
+
+        debug!(attempt = attempts.attempt, ?delay, "retrying request");
+
+        Some(Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            attempts
+        }))
+    }
+
+    fn clone_request(&self, request: &Request) -> Option<Request> {
+        request.try_clone()
+    }
+}
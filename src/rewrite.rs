@@ -0,0 +1,120 @@
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+
+/// Rewrites a Google Drive path into the path used by the media server.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rewrite {
+    #[serde(deserialize_with = "deserialize_regex")]
+    from: Regex,
+    to: String,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(de::Error::custom)
+}
+
+impl Rewrite {
+    fn apply(&self, path: &str) -> String {
+        self.from.replace(path, self.to.as_str()).into_owned()
+    }
+}
+
+/// Apply every rule in order, each rule receiving the output of the previous one.
+///
+/// Paths which are not valid UTF-8 are returned as is.
+pub(crate) fn rewrite_path<'a, I>(rules: I, path: PathBuf) -> PathBuf
+where
+    I: IntoIterator<Item = &'a Rewrite>,
+{
+    let mut rewritten = match path.to_str() {
+        Some(path) => path.to_owned(),
+        None => return path,
+    };
+
+    for rule in rules {
+        rewritten = rule.apply(&rewritten);
+    }
+
+    Path::new(&rewritten).to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rewrite_path, Rewrite};
+    use pretty_assertions::assert_eq;
+    use regex::Regex;
+    use std::path::PathBuf;
+
+    fn rule(from: &str, to: &str) -> Rewrite {
+        Rewrite {
+            from: Regex::new(from).unwrap(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Rules {
+            #[allow(dead_code)]
+            rewrite: Vec<Rewrite>,
+        }
+
+        let err =
+            toml::from_str::<Rules>(r#"rewrite = [{ form = "^/Movies/", to = "/mnt/movies/" }]"#)
+                .unwrap_err();
+        assert!(err.to_string().contains("unknown field `form`"), "{}", err);
+
+        toml::from_str::<Rules>(r#"rewrite = [{ from = "^/Movies/", to = "/mnt/movies/" }]"#)
+            .unwrap();
+    }
+
+    #[test]
+    fn no_rules_keeps_path() {
+        let path = rewrite_path(&[], "/Movies/Foo".into());
+        assert_eq!(path, PathBuf::from("/Movies/Foo"));
+    }
+
+    #[test]
+    fn capture_groups_are_expanded() {
+        let rules = [rule("^/Movies/(.*)", "/mnt/unionfs/Media/Movies/$1")];
+
+        let path = rewrite_path(&rules, "/Movies/Foo (2021)".into());
+        assert_eq!(path, PathBuf::from("/mnt/unionfs/Media/Movies/Foo (2021)"));
+    }
+
+    #[test]
+    fn non_matching_rule_is_skipped() {
+        let rules = [rule("^/TV/", "/mnt/tv/")];
+
+        let path = rewrite_path(&rules, "/Movies/Foo".into());
+        assert_eq!(path, PathBuf::from("/Movies/Foo"));
+    }
+
+    /// Rules are chained, so a later rule sees the result of an earlier rule.
+    #[test]
+    fn rules_are_applied_in_order() {
+        let rules = [
+            rule("^/Movies/", "/media/Movies/"),
+            rule("^/media/", "/mnt/"),
+        ];
+
+        let path = rewrite_path(&rules, "/Movies/Foo".into());
+        assert_eq!(path, PathBuf::from("/mnt/Movies/Foo"));
+
+        // Swapping the rules means the second rule no longer matches the original path.
+        let rules = [
+            rule("^/media/", "/mnt/"),
+            rule("^/Movies/", "/media/Movies/"),
+        ];
+
+        let path = rewrite_path(&rules, "/Movies/Foo".into());
+        assert_eq!(path, PathBuf::from("/media/Movies/Foo"));
+    }
+}
@@ -0,0 +1,298 @@
+use tokio::time::{Duration, Instant};
+
+/// The poll interval is doubled for every consecutive failure, up to this many times.
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+/// Stand-in for a moment too far in the future to represent, roughly 30 years from now.
+const FAR_FUTURE: Duration = Duration::from_secs(86400 * 365 * 30);
+
+/// A Shared Drive together with the moment it should be polled next.
+#[derive(Debug)]
+struct Entry {
+    drive_id: String,
+    interval: Duration,
+    next: Instant,
+    /// Number of consecutive failed syncs.
+    failures: u32,
+}
+
+/// Keeps track of when each Shared Drive is due to be synchronised.
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    entries: Vec<Entry>,
+}
+
+impl Scheduler {
+    /// Create a scheduler where every drive is due immediately.
+    pub(crate) fn new<I>(drives: I, now: Instant) -> Self
+    where
+        I: IntoIterator<Item = (String, Duration)>,
+    {
+        let entries = drives
+            .into_iter()
+            .map(|(drive_id, interval)| Entry {
+                drive_id,
+                interval,
+                next: now,
+                failures: 0,
+            })
+            .collect();
+
+        Self { entries }
+    }
+
+    /// Replace the scheduled drives, keeping the schedule of drives which remain.
+    ///
+    /// New drives are due at `now`, and drives which remain take on their new interval from their next poll,
+    /// or sooner when the new interval ends before that poll.
+    pub(crate) fn update<I>(&mut self, drives: I, now: Instant)
+    where
+        I: IntoIterator<Item = (String, Duration)>,
+    {
+        let mut previous = std::mem::take(&mut self.entries);
+
+        self.entries = drives
+            .into_iter()
+            .map(|(drive_id, interval)| {
+                match previous.iter().position(|e| e.drive_id == drive_id) {
+                    Some(index) => {
+                        let entry = previous.swap_remove(index);
+                        Entry {
+                            interval,
+                            next: entry.next.min(later(now, interval)),
+                            ..entry
+                        }
+                    }
+                    None => Entry {
+                        drive_id,
+                        interval,
+                        next: now,
+                        failures: 0,
+                    },
+                }
+            })
+            .collect();
+    }
+
+    /// All drive IDs which should be polled at `now`.
+    pub(crate) fn due(&self, now: Instant) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.next <= now)
+            .map(|entry| entry.drive_id.clone())
+            .collect()
+    }
+
+    /// Every drive ID known to the scheduler, regardless of whether it is due.
+    pub(crate) fn drives(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| entry.drive_id.clone())
+            .collect()
+    }
+
+    fn entry_mut(&mut self, drive_id: &str) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|e| e.drive_id == drive_id)
+    }
+
+    /// Mark the drive as synchronised at `now`, scheduling its next poll one interval later.
+    pub(crate) fn completed(&mut self, drive_id: &str, now: Instant) {
+        if let Some(entry) = self.entry_mut(drive_id) {
+            entry.failures = 0;
+            entry.next = later(now, entry.interval);
+        }
+    }
+
+    /// Mark the sync of the drive as failed at `now`,
+    /// returning the number of consecutive failures.
+    ///
+    /// With `backoff`, the next poll is delayed exponentially with every consecutive failure.
+    pub(crate) fn failed(&mut self, drive_id: &str, now: Instant, backoff: bool) -> u32 {
+        match self.entry_mut(drive_id) {
+            Some(entry) => {
+                entry.failures += 1;
+
+                let factor = if backoff {
+                    2u32.pow(entry.failures.min(MAX_BACKOFF_EXPONENT))
+                } else {
+                    1
+                };
+
+                entry.next = later(now, entry.interval.saturating_mul(factor));
+                entry.failures
+            }
+            None => 0,
+        }
+    }
+
+    /// The moment the drive is due next, if it is scheduled.
+    pub(crate) fn next(&self, drive_id: &str) -> Option<Instant> {
+        self.entries
+            .iter()
+            .find(|e| e.drive_id == drive_id)
+            .map(|entry| entry.next)
+    }
+
+    /// The earliest moment any drive is due, if any drives are scheduled.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.entries.iter().map(|entry| entry.next).min()
+    }
+}
+
+/// The moment `delay` after `now`, without overflowing for huge delays.
+fn later(now: Instant, delay: Duration) -> Instant {
+    now.checked_add(delay)
+        .unwrap_or_else(|| now + FAR_FUTURE.min(delay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use pretty_assertions::assert_eq;
+    use tokio::time::{Duration, Instant};
+
+    fn scheduler(now: Instant) -> Scheduler {
+        Scheduler::new(
+            vec![
+                ("fast".to_string(), Duration::from_secs(15)),
+                ("slow".to_string(), Duration::from_secs(3600)),
+            ],
+            now,
+        )
+    }
+
+    #[test]
+    fn all_drives_are_due_initially() {
+        let now = Instant::now();
+        let scheduler = scheduler(now);
+
+        assert_eq!(scheduler.due(now), vec!["fast", "slow"]);
+        assert_eq!(scheduler.next_due(), Some(now));
+    }
+
+    #[test]
+    fn drives_follow_their_own_interval() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+
+        scheduler.completed("fast", now);
+        scheduler.completed("slow", now);
+
+        assert_eq!(scheduler.due(now), Vec::<String>::new());
+        assert_eq!(scheduler.next_due(), Some(now + Duration::from_secs(15)));
+        assert_eq!(scheduler.due(now + Duration::from_secs(15)), vec!["fast"]);
+        assert_eq!(
+            scheduler.due(now + Duration::from_secs(3600)),
+            vec!["fast", "slow"]
+        );
+    }
+
+    #[test]
+    fn failures_are_counted_until_completed() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+
+        assert_eq!(scheduler.failed("fast", now, false), 1);
+        assert_eq!(scheduler.failed("fast", now, false), 2);
+        assert_eq!(
+            scheduler.due(now + Duration::from_secs(15)),
+            vec!["fast", "slow"]
+        );
+
+        scheduler.completed("fast", now);
+        assert_eq!(scheduler.failed("fast", now, false), 1);
+    }
+
+    #[test]
+    fn failures_back_off_exponentially() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.completed("slow", now);
+
+        scheduler.failed("fast", now, true);
+        assert_eq!(scheduler.next_due(), Some(now + Duration::from_secs(30)));
+
+        scheduler.failed("fast", now, true);
+        assert_eq!(scheduler.next_due(), Some(now + Duration::from_secs(60)));
+
+        for _ in 0..10 {
+            scheduler.failed("fast", now, true);
+        }
+        assert_eq!(
+            scheduler.next_due(),
+            Some(now + Duration::from_secs(15 * 64))
+        );
+
+        // A successful sync restores the regular interval.
+        scheduler.completed("fast", now);
+        assert_eq!(scheduler.next_due(), Some(now + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn huge_intervals_do_not_overflow() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(vec![("huge".to_string(), Duration::MAX)], now);
+
+        scheduler.completed("huge", now);
+        scheduler.failed("huge", now, true);
+        assert!(scheduler.next_due().unwrap() > now);
+        assert_eq!(scheduler.due(now), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_drive_is_ignored() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+
+        scheduler.completed("unknown", now);
+        assert_eq!(scheduler.failed("unknown", now, true), 0);
+        assert_eq!(scheduler.due(now), vec!["fast", "slow"]);
+    }
+
+    #[test]
+    fn update_keeps_remaining_drives() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.completed("fast", now);
+        scheduler.completed("slow", now);
+        scheduler.failed("slow", now, false);
+
+        let later = now + Duration::from_secs(5);
+        scheduler.update(
+            vec![
+                ("slow".to_string(), Duration::from_secs(60)),
+                ("new".to_string(), Duration::from_secs(15)),
+            ],
+            later,
+        );
+
+        assert_eq!(scheduler.drives(), vec!["slow", "new"]);
+        assert_eq!(scheduler.due(later), vec!["new"]);
+        assert_eq!(scheduler.failed("slow", later, false), 2);
+    }
+
+    #[test]
+    fn update_applies_shorter_intervals_immediately() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.completed("fast", now);
+        scheduler.completed("slow", now);
+
+        let later = now + Duration::from_secs(5);
+        scheduler.update(
+            vec![
+                ("fast".to_string(), Duration::from_secs(3600)),
+                ("slow".to_string(), Duration::from_secs(15)),
+            ],
+            later,
+        );
+
+        // A longer interval only applies from the next poll.
+        assert_eq!(scheduler.next_due(), Some(now + Duration::from_secs(15)));
+        assert_eq!(scheduler.due(now + Duration::from_secs(15)), vec!["fast"]);
+        assert_eq!(
+            scheduler.due(later + Duration::from_secs(15)),
+            vec!["fast", "slow"]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{
+    metrics,
+    status::Status,
+    trigger::{Trigger, TriggerError},
+};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr};
+use tracing::info;
+
+/// Serve the health, status, metrics and sync endpoints until the server fails.
+pub(crate) async fn serve(
+    address: SocketAddr,
+    status: Status,
+    trigger: Trigger,
+) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let status = status.clone();
+        let trigger = trigger.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let status = status.clone();
+                let trigger = trigger.clone();
+                async move { Ok::<_, Infallible>(handle(request, &status, &trigger).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&address)?.serve(make_service);
+    info!(%address, "Listening for HTTP requests.");
+
+    server.await
+}
+
+async fn handle(request: Request<Body>, status: &Status, trigger: &Trigger) -> Response<Body> {
+    let path = request.uri().path();
+
+    if request.method() == Method::POST {
+        if path == "/sync" {
+            return sync(trigger, None).await;
+        }
+
+        if let Some(drive_id) = path.strip_prefix("/sync/") {
+            return sync(trigger, Some(drive_id.to_owned())).await;
+        }
+    }
+
+    match (request.method(), path) {
+        (&Method::GET, "/health") => {
+            if status.is_healthy() {
+                json(StatusCode::OK, r#"{"healthy":true}"#.to_owned())
+            } else {
+                json(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    r#"{"healthy":false}"#.to_owned(),
+                )
+            }
+        }
+        (&Method::GET, "/status") => match status.to_json() {
+            Ok(body) => json(StatusCode::OK, body),
+            Err(_) => empty(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        (&Method::GET, "/metrics") => match metrics::encode() {
+            Ok((content_type, body)) => Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap(),
+            Err(_) => empty(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        _ => empty(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Synchronise one or all drives and respond with the summary of each drive.
+async fn sync(trigger: &Trigger, drive_id: Option<String>) -> Response<Body> {
+    match trigger.sync(drive_id).await {
+        Ok(summaries) => match serde_json::to_string(&summaries) {
+            Ok(body) => json(StatusCode::OK, body),
+            Err(_) => empty(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        Err(TriggerError::UnknownDrive) => empty(StatusCode::NOT_FOUND),
+        Err(TriggerError::Unavailable) => empty(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+fn json(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::handle;
+    use crate::{
+        metrics,
+        status::Status,
+        trigger::{self, Trigger},
+    };
+    use hyper::{body, Body, Method, Request, StatusCode};
+
+    async fn request(method: Method, path: &str, trigger: &Trigger) -> (StatusCode, String) {
+        let status = Status::new(Vec::new(), Vec::new());
+
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+
+        let response = handle(request, &status, trigger).await;
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn metrics_are_exposed() {
+        metrics::SYNCS
+            .with_label_values(&["exposed", "partial"])
+            .inc();
+
+        let (trigger, _requests) = trigger::channel();
+        let (status, body) = request(Method::GET, "/metrics", &trigger).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#"atrain_syncs_total{drive="exposed",kind="partial"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn sync_round_trip() {
+        let (trigger, mut requests) = trigger::channel();
+
+        let handler = tokio::spawn(async move {
+            let request = requests.recv().await.unwrap();
+            let drive_id = request.drive_id.clone();
+            request.respond.send(Some(Vec::new())).unwrap();
+            drive_id
+        });
+
+        let (status, body) = request(Method::POST, "/sync/0A1xxxxxxxxxUk9PVA", &trigger).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "[]");
+        assert_eq!(
+            handler.await.unwrap().as_deref(),
+            Some("0A1xxxxxxxxxUk9PVA")
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_unknown_drive() {
+        let (trigger, mut requests) = trigger::channel();
+
+        tokio::spawn(async move {
+            let request = requests.recv().await.unwrap();
+            request.respond.send(None).unwrap();
+        });
+
+        let (status, _) = request(Method::POST, "/sync/unknown", &trigger).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn sync_while_shutting_down() {
+        let (trigger, requests) = trigger::channel();
+
+        // Requests are no longer received once shutting down.
+        drop(requests);
+        let (status, _) = request(Method::POST, "/sync", &trigger).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // Nor answered when the shutdown starts while the request is queued.
+        let (trigger, mut requests) = trigger::channel();
+        tokio::spawn(async move {
+            drop(requests.recv().await);
+        });
+
+        let (status, _) = request(Method::POST, "/sync", &trigger).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
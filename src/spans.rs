@@ -0,0 +1,177 @@
+use crate::overlay::Override;
+use serde::de::{self, Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt;
+use toml::Spanned;
+
+/// The positions of the values in a configuration file, by key.
+///
+/// Keys are written like in the error messages, e.g. `autoscan[1].url` or `drive.drives[0].id`.
+/// The only element of an array is also found without its index, e.g. `autoscan.url`.
+#[derive(Debug, Default)]
+pub(crate) struct Spans(HashMap<String, (usize, usize)>);
+
+impl Spans {
+    /// Any source which cannot be parsed has no positions.
+    pub(crate) fn new(source: &str, overrides: &[Override]) -> Self {
+        let mut spans = Self::default();
+
+        if let Ok(root) = toml::from_str::<Spanned<Inner>>(source) {
+            spans.insert(source, "", &root);
+        }
+
+        // Overridden values are no longer found in the source.
+        for o in overrides {
+            let key = o.path();
+            spans.0.retain(|k, _| {
+                k != &key
+                    && !k.starts_with(&format!("{}.", key))
+                    && !k.starts_with(&format!("{}[", key))
+            });
+        }
+
+        spans
+    }
+
+    /// One-based line and column of the value at the key.
+    pub(crate) fn get(&self, key: &str) -> Option<(usize, usize)> {
+        self.0.get(key).copied()
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.0.contains_key(key)
+    }
+
+    fn insert(&mut self, source: &str, key: &str, value: &Spanned<Inner>) {
+        // Tables written as a header have no span of their own.
+        if value.start() != value.end() {
+            self.0
+                .insert(key.to_owned(), line_col(source, value.start()));
+        }
+
+        match value.get_ref() {
+            Inner::Scalar => (),
+            Inner::Array(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    self.insert(source, &format!("{}[{}]", key, i), element);
+                }
+
+                if let [element] = elements.as_slice() {
+                    self.insert(source, key, element);
+                }
+            }
+            Inner::Table(entries) => {
+                for (name, entry) in entries {
+                    let key = match key {
+                        "" => name.clone(),
+                        _ => format!("{}.{}", key, name),
+                    };
+
+                    self.insert(source, &key, entry);
+                }
+            }
+        }
+    }
+}
+
+fn line_col(source: &str, index: usize) -> (usize, usize) {
+    let before = &source[..index];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rfind('\n')
+        .map_or(index, |newline| index - newline - 1)
+        + 1;
+
+    (line, column)
+}
+
+/// Any TOML value, only keeping the structure to find the spans of nested values.
+enum Inner {
+    Scalar,
+    Array(Vec<Spanned<Inner>>),
+    Table(Vec<(String, Spanned<Inner>)>),
+}
+
+impl<'de> Deserialize<'de> for Inner {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(InnerVisitor)
+    }
+}
+
+struct InnerVisitor;
+
+impl<'de> de::Visitor<'de> for InnerVisitor {
+    type Value = Inner;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any TOML value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Inner, E> {
+        Ok(Inner::Scalar)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Inner, E> {
+        Ok(Inner::Scalar)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Inner, E> {
+        Ok(Inner::Scalar)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Inner, E> {
+        Ok(Inner::Scalar)
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Inner, E> {
+        Ok(Inner::Scalar)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Inner, A::Error> {
+        let mut elements = Vec::new();
+        while let Some(element) = seq.next_element()? {
+            elements.push(element);
+        }
+
+        Ok(Inner::Array(elements))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Inner, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+
+        Ok(Inner::Table(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Spans;
+    use crate::overlay::Override;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn nested_values() {
+        let source = r#"
+[[autoscan]]
+url = "http://localhost:3030"
+
+[drive]
+interval = 5
+drives = ["0A1xxxxxxxxxUk9PVA", { id = "0A2xxxxxxxxxUk9PVA" }]
+"#;
+
+        let spans = Spans::new(source, &[]);
+        assert_eq!(spans.get("autoscan[0].url"), Some((3, 7)));
+        assert_eq!(spans.get("autoscan.url"), Some((3, 7)));
+        assert_eq!(spans.get("drive.interval"), Some((6, 12)));
+        assert_eq!(spans.get("drive.drives[0]"), Some((7, 11)));
+        assert_eq!(spans.get("drive.drives[1].id"), Some((7, 40)));
+
+        let spans = Spans::new(source, &[Override::from_arg("drive.drives=[]").unwrap()]);
+        assert_eq!(spans.get("drive.drives[1].id"), None);
+        assert_eq!(spans.get("drive.interval"), Some((6, 12)));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::drive::Summary;
+use chrono::{DateTime, Duration as ChronoDuration, Utc, MAX_DATETIME};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    error::Error as StdError,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Grace period on top of the interval a sync may take past its due time.
+const STALE_GRACE: Duration = Duration::from_secs(60);
+
+/// The state of every drive and target, shared with the HTTP server.
+#[derive(Clone, Debug)]
+pub(crate) struct Status(Arc<Mutex<State>>);
+
+#[derive(Debug, Serialize)]
+struct State {
+    started: DateTime<Utc>,
+    drives: BTreeMap<String, DriveStatus>,
+    targets: BTreeMap<String, TargetStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct DriveStatus {
+    name: String,
+    #[serde(skip)]
+    interval: Duration,
+    /// When the next sync is due according to the schedule, including any backoff.
+    #[serde(skip)]
+    due: DateTime<Utc>,
+    last_sync: Option<DateTime<Utc>>,
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    /// Number of consecutive failed syncs.
+    failures: u32,
+    /// Number of paths sent since A-Train started.
+    paths_sent: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct TargetStatus {
+    reachable: Option<bool>,
+    last_checked: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+/// Format an error together with all of its sources.
+pub(crate) fn error_chain(err: &dyn StdError) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+
+    message
+}
+
+impl Status {
+    pub(crate) fn new<'a, D, T>(drives: D, targets: T) -> Self
+    where
+        D: IntoIterator<Item = (&'a str, &'a str, Duration)>,
+        T: IntoIterator<Item = &'a str>,
+    {
+        let status = Self(Arc::new(Mutex::new(State {
+            started: Utc::now(),
+            drives: BTreeMap::new(),
+            targets: BTreeMap::new(),
+        })));
+
+        status.update(drives, targets);
+        status
+    }
+
+    /// Replace the drives and targets, keeping the state of those which remain.
+    pub(crate) fn update<'a, D, T>(&self, drives: D, targets: T)
+    where
+        D: IntoIterator<Item = (&'a str, &'a str, Duration)>,
+        T: IntoIterator<Item = &'a str>,
+    {
+        let mut state = self.0.lock().unwrap();
+        let now = Utc::now();
+
+        let drives = drives
+            .into_iter()
+            .map(|(id, name, interval)| {
+                let status = match state.drives.remove(id) {
+                    Some(status) => DriveStatus {
+                        name: name.to_owned(),
+                        interval,
+                        ..status
+                    },
+                    None => DriveStatus {
+                        name: name.to_owned(),
+                        interval,
+                        due: now,
+                        last_sync: None,
+                        last_success: None,
+                        last_error: None,
+                        failures: 0,
+                        paths_sent: 0,
+                    },
+                };
+
+                (id.to_owned(), status)
+            })
+            .collect();
+
+        let targets = targets
+            .into_iter()
+            .map(|name| {
+                let status = state.targets.remove(name).unwrap_or_default();
+                (name.to_owned(), status)
+            })
+            .collect();
+
+        state.drives = drives;
+        state.targets = targets;
+    }
+
+    pub(crate) fn synced(&self, drive_id: &str, summary: &Summary) {
+        let mut state = self.0.lock().unwrap();
+
+        if let Some(drive) = state.drives.get_mut(drive_id) {
+            let now = Utc::now();
+            drive.last_sync = Some(now);
+            drive.last_success = Some(now);
+            drive.last_error = None;
+            drive.failures = 0;
+            drive.paths_sent += summary.paths() as u64;
+        }
+    }
+
+    pub(crate) fn failed(&self, drive_id: &str, err: &dyn StdError, failures: u32) {
+        let mut state = self.0.lock().unwrap();
+
+        if let Some(drive) = state.drives.get_mut(drive_id) {
+            drive.last_sync = Some(Utc::now());
+            drive.last_error = Some(error_chain(err));
+            drive.failures = failures;
+        }
+    }
+
+    /// Record when the drive is due next according to the scheduler, unscheduled drives are left as is.
+    pub(crate) fn scheduled(&self, drive_id: &str, next: Option<Instant>) {
+        let mut state = self.0.lock().unwrap();
+
+        if let (Some(drive), Some(next)) = (state.drives.get_mut(drive_id), next) {
+            let delay = next.saturating_duration_since(Instant::now());
+            drive.due = ChronoDuration::from_std(delay)
+                .ok()
+                .and_then(|delay| Utc::now().checked_add_signed(delay))
+                .unwrap_or(MAX_DATETIME);
+        }
+    }
+
+    /// Record whether a request to the target succeeded.
+    pub(crate) fn target(&self, name: &str, err: Option<&dyn StdError>) {
+        let mut state = self.0.lock().unwrap();
+
+        if let Some(target) = state.targets.get_mut(name) {
+            target.reachable = Some(err.is_none());
+            target.last_checked = Some(Utc::now());
+            target.last_error = err.map(error_chain);
+        }
+    }
+
+    /// Healthy when no drive is overdue, that is when every drive has been synchronised
+    /// within one interval plus a grace period of the moment it was due.
+    ///
+    /// Follows the schedule, so a drive backing off after failures is only overdue once its retry is.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.is_healthy_at(Utc::now())
+    }
+
+    fn is_healthy_at(&self, now: DateTime<Utc>) -> bool {
+        let state = self.0.lock().unwrap();
+
+        state.drives.values().all(|drive| {
+            // A negative duration cannot be converted and is never overdue.
+            match now.signed_duration_since(drive.due).to_std() {
+                Ok(overdue) => overdue <= drive.interval + STALE_GRACE,
+                Err(_) => true,
+            }
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&*self.0.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Status;
+    use crate::drive::Summary;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use std::time::Duration;
+    use thiserror::Error;
+    use tokio::time::Instant;
+
+    #[derive(Debug, Error)]
+    #[error("{0}")]
+    struct TestError(&'static str);
+
+    fn status() -> Status {
+        Status::new(
+            vec![("0A1", "Movies", Duration::from_secs(60))],
+            vec!["plex"],
+        )
+    }
+
+    #[test]
+    fn healthy_within_threshold() {
+        let status = status();
+        let now = Utc::now();
+
+        assert!(status.is_healthy_at(now));
+        assert!(status.is_healthy_at(now + ChronoDuration::seconds(119)));
+        assert!(!status.is_healthy_at(now + ChronoDuration::seconds(121)));
+    }
+
+    /// A drive backing off is not overdue before its retry is due.
+    #[test]
+    fn backoff_is_not_stale() {
+        let status = status();
+        let err = TestError("boom");
+
+        status.failed("0A1", &err, 6);
+        status.scheduled("0A1", Some(Instant::now() + Duration::from_secs(64 * 60)));
+
+        let now = Utc::now();
+        assert!(status.is_healthy_at(now + ChronoDuration::minutes(65)));
+        assert!(!status.is_healthy_at(now + ChronoDuration::minutes(67)));
+    }
+
+    #[test]
+    fn failures_make_drive_stale() {
+        let status = status();
+        let err = TestError("boom");
+
+        status.failed("0A1", &err, 1);
+
+        let json: Value = serde_json::from_str(&status.to_json().unwrap()).unwrap();
+        assert_eq!(json["drives"]["0A1"]["last_error"], "boom");
+        assert_eq!(json["drives"]["0A1"]["failures"], 1);
+        assert_eq!(json["drives"]["0A1"]["last_success"], Value::Null);
+
+        // Still healthy until the drive has not synchronised for too long.
+        assert!(status.is_healthy_at(Utc::now()));
+        assert!(!status.is_healthy_at(Utc::now() + ChronoDuration::minutes(5)));
+    }
+
+    #[test]
+    fn sync_resets_failures() {
+        let status = status();
+        let err = TestError("boom");
+
+        status.failed("0A1", &err, 3);
+        status.synced(
+            "0A1",
+            &Summary {
+                created: 2,
+                deleted: 1,
+                ..Default::default()
+            },
+        );
+        status.synced(
+            "0A1",
+            &Summary {
+                created: 1,
+                deleted: 0,
+                ..Default::default()
+            },
+        );
+
+        let json: Value = serde_json::from_str(&status.to_json().unwrap()).unwrap();
+        assert_eq!(json["drives"]["0A1"]["name"], "Movies");
+        assert_eq!(json["drives"]["0A1"]["last_error"], Value::Null);
+        assert_eq!(json["drives"]["0A1"]["failures"], 0);
+        assert_eq!(json["drives"]["0A1"]["paths_sent"], 4);
+    }
+
+    #[test]
+    fn target_reachability() {
+        let status = status();
+        let err = TestError("connection refused");
+
+        status.target("plex", Some(&err));
+        let json: Value = serde_json::from_str(&status.to_json().unwrap()).unwrap();
+        assert_eq!(json["targets"]["plex"]["reachable"], false);
+        assert_eq!(json["targets"]["plex"]["last_error"], "connection refused");
+
+        status.target("plex", None);
+        let json: Value = serde_json::from_str(&status.to_json().unwrap()).unwrap();
+        assert_eq!(json["targets"]["plex"]["reachable"], true);
+    }
+
+    #[test]
+    fn update_keeps_remaining_drives() {
+        let status = status();
+        status.synced(
+            "0A1",
+            &Summary {
+                created: 2,
+                deleted: 0,
+                ..Default::default()
+            },
+        );
+
+        status.update(
+            vec![
+                ("0A1", "Films", Duration::from_secs(60)),
+                ("0A2", "Shows", Duration::from_secs(60)),
+            ],
+            vec!["emby"],
+        );
+
+        let json: Value = serde_json::from_str(&status.to_json().unwrap()).unwrap();
+        assert_eq!(json["drives"]["0A1"]["name"], "Films");
+        assert_eq!(json["drives"]["0A1"]["paths_sent"], 2);
+        assert_eq!(json["drives"]["0A2"]["last_success"], Value::Null);
+        assert_eq!(json["targets"]["plex"], Value::Null);
+        assert!(json["targets"]["emby"].is_object());
+    }
+}
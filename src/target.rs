@@ -0,0 +1,147 @@
+use crate::autoscan::{Autoscan, AutoscanBuilder, AutoscanError, Payload};
+use crate::config::{AutoscanConfig, Paths, WebhookConfig};
+use crate::webhook::{Webhook, WebhookBuilder, WebhookError};
+use reqwest::IntoUrl;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TargetError {
+    #[error("Autoscan")]
+    Autoscan(#[from] AutoscanError),
+    #[error("webhook")]
+    Webhook(#[from] WebhookError),
+}
+
+/// An Autoscan instance or webhook which receives the changes of some or all Shared Drives.
+pub(crate) struct Target {
+    pub(crate) name: String,
+    output: Output,
+    drives: Option<HashSet<String>>,
+    max_paths: Option<NonZeroUsize>,
+    pub(crate) paths: Paths,
+}
+
+enum Output {
+    Autoscan(Autoscan),
+    Webhook(Webhook),
+}
+
+impl Target {
+    /// Whether the changes of the given drive should be sent to this target.
+    pub(crate) fn accepts(&self, drive_id: &str) -> bool {
+        match &self.drives {
+            Some(drives) => drives.contains(drive_id),
+            None => true,
+        }
+    }
+
+    /// The part of the payload this target receives, split into chunks when it is too large.
+    ///
+    /// A webhook in path mode receives a chunk for every request instead.
+    pub(crate) fn payloads(&self, payload: &Payload) -> Vec<Payload> {
+        let payload = payload.select(self.paths);
+
+        match (&self.output, self.max_paths) {
+            (Output::Webhook(webhook), _) if webhook.per_path() => Webhook::split(&payload),
+            (_, Some(max_paths)) => payload.chunks(max_paths.get()),
+            (_, None) => vec![payload],
+        }
+    }
+
+    pub(crate) async fn available(&self) -> Result<(), TargetError> {
+        match &self.output {
+            Output::Autoscan(autoscan) => Ok(autoscan.available().await?),
+            Output::Webhook(webhook) => Ok(webhook.available().await?),
+        }
+    }
+
+    pub(crate) async fn send_payload(
+        &self,
+        trigger: &str,
+        drive_id: &str,
+        payload: &Payload,
+    ) -> Result<(), TargetError> {
+        match &self.output {
+            Output::Autoscan(autoscan) => {
+                Ok(autoscan.send_payload(trigger, drive_id, payload).await?)
+            }
+            Output::Webhook(webhook) => {
+                Ok(webhook.send_payload(trigger, drive_id, payload).await?)
+            }
+        }
+    }
+}
+
+pub(crate) struct TargetBuilder {
+    name: String,
+    output: OutputBuilder,
+    drives: Option<HashSet<String>>,
+    max_paths: Option<NonZeroUsize>,
+    paths: Paths,
+}
+
+enum OutputBuilder {
+    Autoscan(AutoscanBuilder),
+    Webhook(WebhookBuilder),
+}
+
+impl TargetBuilder {
+    pub(crate) fn new(config: AutoscanConfig) -> Self {
+        let name = config.name().into_owned();
+
+        let mut autoscan = Autoscan::builder(&config.url, config.credentials())
+            .name(&name)
+            .retry((&config.retry).into());
+        if let Some(proxy) = config.proxy {
+            autoscan = autoscan.proxy(proxy);
+        }
+
+        Self {
+            name,
+            output: OutputBuilder::Autoscan(autoscan),
+            drives: config.drives.map(|drives| drives.into_iter().collect()),
+            max_paths: config.max_paths,
+            paths: config.paths,
+        }
+    }
+
+    pub(crate) fn webhook(mut config: WebhookConfig) -> Self {
+        let name = config.name().into_owned();
+        let drives = config.drives.take();
+        let max_paths = config.max_paths;
+        let paths = config.paths;
+
+        Self {
+            name,
+            output: OutputBuilder::Webhook(WebhookBuilder::new(config)),
+            drives: drives.map(|drives| drives.into_iter().collect()),
+            max_paths,
+            paths,
+        }
+    }
+
+    pub(crate) fn proxy<U: IntoUrl>(mut self, url: U) -> Self {
+        self.output = match self.output {
+            OutputBuilder::Autoscan(autoscan) => OutputBuilder::Autoscan(autoscan.proxy(url)),
+            OutputBuilder::Webhook(webhook) => OutputBuilder::Webhook(webhook.proxy(url)),
+        };
+        self
+    }
+
+    pub(crate) fn build(self) -> Result<Target, TargetError> {
+        let output = match self.output {
+            OutputBuilder::Autoscan(autoscan) => Output::Autoscan(autoscan.build()?),
+            OutputBuilder::Webhook(webhook) => Output::Webhook(webhook.build()?),
+        };
+
+        Ok(Target {
+            name: self.name,
+            output,
+            drives: self.drives,
+            max_paths: self.max_paths,
+            paths: self.paths,
+        })
+    }
+}
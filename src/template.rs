@@ -0,0 +1,134 @@
+use serde::{Deserialize, Deserializer};
+
+/// A string with `{{name}}` placeholders, e.g. `http://indexer:8080/changes/{{drive_id}}`.
+///
+/// Anything else between braces is kept as is, so JSON bodies need no escaping.
+#[derive(Clone, Debug)]
+pub(crate) struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(String),
+}
+
+impl Template {
+    pub(crate) fn parse(source: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = source;
+
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+
+            let name = after
+                .find("}}")
+                .map(|end| &after[..end])
+                .filter(|name| is_placeholder(name.trim()));
+
+            match name {
+                Some(name) => {
+                    literal.push_str(&rest[..start]);
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    parts.push(Part::Placeholder(name.trim().to_owned()));
+                    rest = &after[name.len() + 2..];
+                }
+                // The second brace might start a placeholder, e.g. `{{{name}}}`.
+                None => {
+                    literal.push_str(&rest[..start + 1]);
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Self {
+            source: source.to_owned(),
+            parts,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub(crate) fn placeholders(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Placeholder(name) => Some(name.as_str()),
+            Part::Literal(_) => None,
+        })
+    }
+
+    /// Replace every placeholder with the value returned for its name.
+    pub(crate) fn render<F>(&self, value: F) -> String
+    where
+        F: Fn(&str) -> String,
+    {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Placeholder(name) => value(name),
+            })
+            .collect()
+    }
+}
+
+fn is_placeholder(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let source = String::deserialize(deserializer)?;
+        Ok(Self::parse(&source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Template;
+    use pretty_assertions::assert_eq;
+
+    fn render(source: &str) -> String {
+        Template::parse(source).render(|name| name.to_uppercase())
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            render("http://indexer/{{drive_id}}/{{ trigger }}"),
+            "http://indexer/DRIVE_ID/TRIGGER"
+        );
+
+        let template = Template::parse("{{created}} and {{deleted}}");
+        assert_eq!(
+            template.placeholders().collect::<Vec<_>>(),
+            vec!["created", "deleted"]
+        );
+    }
+
+    #[test]
+    fn json_braces_are_kept() {
+        assert_eq!(
+            render(r#"{"created": {{created}}, "nested": {"a": {"b": 1}}}"#),
+            r#"{"created": CREATED, "nested": {"a": {"b": 1}}}"#
+        );
+        assert_eq!(render("{{not a placeholder}}"), "{{not a placeholder}}");
+        assert_eq!(render("{{unclosed"), "{{unclosed");
+        assert_eq!(render("{{{created}}}"), "{CREATED}");
+    }
+}
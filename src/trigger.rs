@@ -0,0 +1,94 @@
+use crate::{drive::Summary, status::error_chain, Atrain, Result};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of manual sync requests which can be queued while a sync is in progress.
+const QUEUE_SIZE: usize = 16;
+
+/// A request to synchronise one or all drives immediately.
+#[derive(Debug)]
+pub(crate) struct SyncRequest {
+    /// Synchronise all drives when not set.
+    pub(crate) drive_id: Option<String>,
+    /// Responds with `None` when the drive is unknown.
+    pub(crate) respond: oneshot::Sender<Option<Vec<DriveSummary>>>,
+}
+
+/// The outcome of a manual sync of a single drive.
+#[derive(Debug, Serialize)]
+pub(crate) struct DriveSummary {
+    drive_id: String,
+    name: String,
+    #[serde(flatten)]
+    summary: Option<Summary>,
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) enum TriggerError {
+    /// The requested drive is not configured or disabled.
+    UnknownDrive,
+    /// A-Train is shutting down.
+    Unavailable,
+}
+
+/// Handle used by the HTTP server to request manual syncs.
+#[derive(Clone, Debug)]
+pub(crate) struct Trigger(mpsc::Sender<SyncRequest>);
+
+pub(crate) fn channel() -> (Trigger, mpsc::Receiver<SyncRequest>) {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    (Trigger(sender), receiver)
+}
+
+impl Trigger {
+    pub(crate) async fn sync(
+        &self,
+        drive_id: Option<String>,
+    ) -> std::result::Result<Vec<DriveSummary>, TriggerError> {
+        let (respond, response) = oneshot::channel();
+        let request = SyncRequest { drive_id, respond };
+
+        self.0
+            .send(request)
+            .await
+            .map_err(|_| TriggerError::Unavailable)?;
+
+        response
+            .await
+            .map_err(|_| TriggerError::Unavailable)?
+            .ok_or(TriggerError::UnknownDrive)
+    }
+}
+
+impl Atrain {
+    /// Synchronise the requested drives and respond with the summary of each drive.
+    pub(crate) async fn handle_sync_request(&self, request: SyncRequest) -> Result<()> {
+        let settings = self.settings();
+        let drives = match request.drive_id {
+            Some(drive_id) if settings.drives.contains_key(&drive_id) => vec![drive_id],
+            Some(_) => {
+                let _ = request.respond.send(None);
+                return Ok(());
+            }
+            None => self.scheduler.lock().unwrap().drives(),
+        };
+
+        let results = self.sync_drives(&settings, &drives).await;
+
+        let summaries = results
+            .iter()
+            .map(|(drive, result)| DriveSummary {
+                drive_id: drive.id.clone(),
+                name: drive.display_name().to_owned(),
+                summary: result.as_ref().ok().copied(),
+                error: result.as_ref().err().map(|err| error_chain(err)),
+            })
+            .collect();
+
+        // The requester might have gone away, which is fine.
+        let _ = request.respond.send(Some(summaries));
+
+        self.reschedule(&settings, results)
+    }
+}